@@ -3,7 +3,7 @@ use std::{net::IpAddr, path::{Path, PathBuf}, time::Duration};
 use log::LevelFilter;
 
 use structopt::StructOpt;
-use penguin::{Mount, ProxyTarget};
+use penguin::{Cors, CustomHeader, InjectPosition, Mount, ProxyTarget};
 
 pub(crate) const DEFAULT_PORT: u16 = 4090;
 
@@ -47,6 +47,16 @@ pub(crate) struct Args {
     #[structopt(long, global = true)]
     pub(crate) open: bool,
 
+    /// Load settings from this configuration file instead of 'penguin.toml'.
+    ///
+    /// By default, 'penguin.toml' in the current directory is loaded if it
+    /// exists. Flags given on the command line take precedence over the file.
+    /// Relative paths in the file are relative to the file's directory. When
+    /// the file changes, mounts and the proxy target are updated without
+    /// restarting penguin.
+    #[structopt(long, global = true, parse(from_os_str))]
+    pub(crate) config: Option<PathBuf>,
+
     #[structopt(subcommand)]
     pub(crate) cmd: Command,
 }
@@ -57,11 +67,12 @@ pub(crate) enum Command {
     ///
     /// You can mount more directories via '--mount'. If you don't specify a
     /// main directory for this subcommand, you have to mount at least one
-    /// directory via '--mount'.
+    /// directory via '--mount' or the configuration file.
     ///
     /// Like with `--mount`, the directory specified here will be watched for
     /// file changes to automatically reload browser sessions. You can disable
-    /// that with `--no-auto-watch`.
+    /// that with `--no-auto-watch`. To follow symlinks pointing outside of it,
+    /// mount it via '--mount /:<path>:follow-symlinks' instead.
     Serve {
         #[structopt(parse(from_os_str))]
         path: Option<PathBuf>,
@@ -71,8 +82,21 @@ pub(crate) enum Command {
     },
 
     /// Starts a server forwarding all request to the specified target address.
+    ///
+    /// The target can be omitted if it is specified via 'proxy' in the
+    /// configuration file.
     Proxy {
-        target: ProxyTarget,
+        target: Option<ProxyTarget>,
+
+        /// Start the backend server with this shell command and supervise it.
+        ///
+        /// Example: 'penguin proxy localhost:8000 --run "cargo run" --watch src'.
+        /// The backend's output is forwarded with a '[backend]' prefix. When a
+        /// path specified via '--watch' changes, the backend is stopped and
+        /// started again. Browser sessions are reloaded once the new instance
+        /// accepts connections on the proxy target.
+        #[structopt(long, conflicts_with = "exec")]
+        run: Option<String>,
 
         #[structopt(flatten)]
         options: ServeOptions,
@@ -96,6 +120,11 @@ pub(crate) struct ServeOptions {
     /// By default, directories specified here will be watched for file changes
     /// to automatically reload browser sessions. You can disable that with
     /// `--no-auto-watch`.
+    ///
+    /// Append ':follow-symlinks' to follow symlinks pointing outside of the
+    /// mounted directory, e.g. '--mount modules:node_modules:follow-symlinks'.
+    /// By default, requests to such files are rejected. Requests with '..' in
+    /// their path are always rejected.
     #[structopt(
         short,
         long = "--mount",
@@ -104,6 +133,84 @@ pub(crate) struct ServeOptions {
     )]
     pub(crate) mounts: Vec<Mount>,
 
+    /// Sets the MIME type for files with a specific extension: '--mime <ext>=<type>'.
+    ///
+    /// Example: '--mime glb=model/gltf-binary'. Can be specified multiple
+    /// times. Overrides the MIME type that would otherwise be guessed.
+    #[structopt(
+        long = "--mime",
+        number_of_values = 1,
+        parse(try_from_str = parse_mime_type),
+    )]
+    pub(crate) mime_types: Vec<(String, String)>,
+
+    /// Add a header to all responses of the file server: '--header "<name>: <value>"'.
+    ///
+    /// Example: '--header "Cross-Origin-Opener-Policy: same-origin"'. Can be
+    /// specified multiple times. Replaces headers with the same name that
+    /// penguin would send otherwise. To only add headers for some paths, use
+    /// 'headers' in the configuration file.
+    #[structopt(
+        long = "--header",
+        number_of_values = 1,
+        parse(try_from_str = parse_header),
+    )]
+    pub(crate) headers: Vec<CustomHeader>,
+
+    /// Also add headers specified via '--header' to responses from the proxy.
+    #[structopt(long)]
+    pub(crate) proxy_headers: bool,
+
+    /// Allow cross-origin requests from all origins, with all methods and
+    /// headers, but without credentials.
+    ///
+    /// Penguin answers CORS preflight requests and adds 'Access-Control-*'
+    /// headers to responses of the file server and the proxy. Useful if
+    /// another dev server (on another port) fetches from penguin. To only
+    /// allow some origins, methods or headers or to allow credentials, use
+    /// 'cors' in the configuration file.
+    #[structopt(long)]
+    pub(crate) cors: bool,
+
+    /// CORS settings from the configuration file.
+    #[structopt(skip)]
+    pub(crate) cors_config: Option<Cors>,
+
+    /// Where to inject the penguin script into HTML pages: 'head-start',
+    /// 'head-end' or 'body-end'.
+    ///
+    /// Use 'head-start' if the script needs to be loaded before all other
+    /// scripts on the page.
+    #[structopt(
+        long,
+        default_value = "body-end",
+        parse(try_from_str = parse_inject_position),
+    )]
+    pub(crate) inject_position: InjectPosition,
+
+    /// Print the browsers' console output and uncaught JS errors in the
+    /// terminal.
+    ///
+    /// Useful when testing on devices without easily accessible dev tools,
+    /// e.g. phones (see '--bind').
+    #[structopt(long)]
+    pub(crate) forward_console: bool,
+
+    /// Don't restore the scroll position, focus and form field values after
+    /// reloads triggered by penguin.
+    #[structopt(long)]
+    pub(crate) no_preserve_state: bool,
+
+    /// Mirror navigation, scrolling and clicks between all connected
+    /// browsers, e.g. to test a page on multiple devices at the same time.
+    #[structopt(long)]
+    pub(crate) sync: bool,
+
+    /// Show a small badge on the page indicating whether the browser is
+    /// connected to penguin.
+    #[structopt(long)]
+    pub(crate) connection_badge: bool,
+
     /// When specified, penguin will not automatically watch the mounted paths.
     #[structopt(long)]
     pub(crate) no_auto_watch: bool,
@@ -114,12 +221,63 @@ pub(crate) struct ServeOptions {
     #[structopt(short, long = "--watch", number_of_values = 1)]
     pub(crate) watched_paths: Vec<PathBuf>,
 
+    /// Run a shell command whenever a watched path changes and reload once it
+    /// succeeded.
+    ///
+    /// Example: '--exec "npm run build" --watch src'. While the command is
+    /// running, a "building" indicator is shown in the browser. If it fails,
+    /// its stderr output is shown instead. If files change while the command
+    /// is running, it is killed and restarted. Mounted directories are not
+    /// watched automatically in this mode, so at least one '--watch' path is
+    /// required.
+    #[structopt(long)]
+    pub(crate) exec: Option<String>,
+
+    /// Ignore file changes in watched paths matching this glob.
+    ///
+    /// Example: '--ignore "*.tmp" --ignore node_modules'. Can be specified
+    /// multiple times. Globs without '/' are matched against every component
+    /// of a changed path, so 'node_modules' ignores everything inside such a
+    /// directory. Globs with '/' are matched against the path relative to the
+    /// watched directory. Temporary files of common editors and VCS
+    /// directories (e.g. '.git') are always ignored.
+    #[structopt(long = "--ignore", number_of_values = 1)]
+    pub(crate) ignored: Vec<String>,
+
+    /// Only react to file changes in watched paths matching this glob.
+    ///
+    /// Example: '--watch-only "*.css"'. Can be specified multiple times, in
+    /// which case a path has to match any of them. Matched like '--ignore'.
+    #[structopt(long = "--watch-only", number_of_values = 1)]
+    pub(crate) watch_only: Vec<String>,
+
+    /// Only reload when this file is created or modified.
+    ///
+    /// Example: '--trigger-file .build-done'. Useful for build tools that
+    /// write many files over a longer time and touch a marker file once they
+    /// are done. Mounts and paths specified via '--watch' do not trigger
+    /// reloads in this mode.
+    #[structopt(long, parse(from_os_str), conflicts_with = "exec")]
+    pub(crate) trigger_file: Option<PathBuf>,
+
+    /// When specified, '.gitignore' files are not honored when watching paths.
+    #[structopt(long)]
+    pub(crate) no_gitignore: bool,
+
+    /// Poll watched paths for changes every this many ms instead of relying on
+    /// file system events.
+    ///
+    /// Use this if changes are not picked up otherwise, e.g. on network file
+    /// systems, Docker bind mounts or in WSL. Example: '--poll 500'.
+    #[structopt(long = "--poll", parse(try_from_str = parse_duration))]
+    pub(crate) poll_interval: Option<Duration>,
+
     /// The debounce duration (in ms) for watching paths.
     ///
     /// Debouncing means that if a watch-event arrived, we are not immediately
     /// triggering a reload. Instead we wait for this duration and see if any
     /// other events arrive during this period. Whenever an event arrives, we
-    /// reset the timer (so we could wait indefinitely).
+    /// reset the timer, but never wait longer than '--max-wait' in total.
     #[structopt(
         long = "--debounce",
         default_value = "200",
@@ -141,9 +299,25 @@ pub(crate) struct ServeOptions {
         parse(try_from_str = parse_duration)
     )]
     pub(crate) removal_debounce_duration: Duration,
+
+    /// The maximum time (in ms) to wait for file changes to settle.
+    ///
+    /// If watch-events keep arriving, for example during a long build, the
+    /// debounce timer keeps being reset. After this duration, the reload is
+    /// triggered anyway.
+    #[structopt(
+        long = "--max-wait",
+        default_value = "10000",
+        parse(try_from_str = parse_duration)
+    )]
+    pub(crate) max_wait: Duration,
 }
 
 fn parse_mount(s: &str) -> Result<Mount, &'static str> {
+    let (s, follow_symlinks) = match s.strip_suffix(":follow-symlinks") {
+        Some(s) => (s, true),
+        None => (s, false),
+    };
     let colon_pos = s.find(':').ok_or("does not contain a colon")?;
     let fs_path = Path::new(&s[colon_pos + 1..]).to_owned();
 
@@ -155,7 +329,38 @@ fn parse_mount(s: &str) -> Result<Mount, &'static str> {
         uri_path.pop();
     }
 
-    Ok(Mount { uri_path, fs_path})
+    Ok(Mount { uri_path, fs_path, follow_symlinks })
+}
+
+fn parse_mime_type(s: &str) -> Result<(String, String), &'static str> {
+    let (extension, mime_type) = s.split_once('=').ok_or("does not contain '='")?;
+    if extension.is_empty() || mime_type.is_empty() {
+        return Err("extension or MIME type is empty");
+    }
+
+    Ok((extension.to_owned(), mime_type.to_owned()))
+}
+
+fn parse_header(s: &str) -> Result<CustomHeader, &'static str> {
+    let (name, value) = s.split_once(':').ok_or("does not contain ':'")?;
+    if name.trim().is_empty() {
+        return Err("header name is empty");
+    }
+
+    Ok(CustomHeader {
+        route: "*".into(),
+        name: name.trim().to_owned(),
+        value: value.trim().to_owned(),
+    })
+}
+
+fn parse_inject_position(s: &str) -> Result<InjectPosition, &'static str> {
+    match s {
+        "head-start" => Ok(InjectPosition::HeadStart),
+        "head-end" => Ok(InjectPosition::HeadEnd),
+        "body-end" => Ok(InjectPosition::BodyEnd),
+        _ => Err("invalid value, valid values are 'head-start', 'head-end' and 'body-end'"),
+    }
 }
 
 fn parse_duration(s: &str) -> Result<Duration, &'static str> {
@@ -172,3 +377,26 @@ impl Args {
         self.quiet == 2
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::parse_mount;
+
+    #[test]
+    fn mounts() {
+        let mount = parse_mount("assets/:static").unwrap();
+        assert_eq!(mount.uri_path, "/assets");
+        assert_eq!(mount.fs_path, PathBuf::from("static"));
+        assert!(!mount.follow_symlinks);
+
+        let mount = parse_mount("modules:node_modules:follow-symlinks").unwrap();
+        assert_eq!(mount.uri_path, "/modules");
+        assert_eq!(mount.fs_path, PathBuf::from("node_modules"));
+        assert!(mount.follow_symlinks);
+
+        assert!(parse_mount("static").is_err());
+    }
+}
@@ -0,0 +1,225 @@
+use std::{process::Stdio, time::Duration};
+
+use penguin::{Controller, ProxyTarget, util::wait_for_proxy};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    process::Child,
+    sync::mpsc::UnboundedReceiver,
+};
+
+use crate::exec::{own_process_group, shell};
+
+
+/// How long the backend gets to shut down gracefully before it is killed.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Starts `command` as backend server and restarts it whenever a change
+/// arrives via `changes`. Once a newly started backend accepts connections on
+/// `target`, all browser sessions are reloaded.
+pub(crate) async fn supervise(
+    command: String,
+    target: ProxyTarget,
+    mut changes: UnboundedReceiver<()>,
+    controller: Controller,
+    muted: bool,
+) {
+    let mut shutdown = Shutdown::listen();
+    let mut changes_closed = false;
+
+    'backend: loop {
+        if !muted {
+            bunt::println!("{$cyan+bold}▸ Starting backend{/$} {[yellow]}", command);
+        }
+
+        let mut child = match spawn(&command) {
+            Ok(child) => child,
+            Err(e) => {
+                bunt::eprintln!(
+                    "{$red+bold}✘ Failed to start backend{/$} {[yellow]}: {}",
+                    command,
+                    e,
+                );
+                if changes_closed || !next_change(&mut changes, &mut shutdown).await {
+                    break 'backend;
+                }
+                continue;
+            }
+        };
+
+        let ready = wait_for_proxy(&target, Duration::from_millis(100));
+        tokio::pin!(ready);
+        let mut is_ready = false;
+
+        loop {
+            tokio::select! {
+                () = &mut ready, if !is_ready => {
+                    is_ready = true;
+                    if !muted {
+                        bunt::println!(
+                            "{$green+bold}✔ Backend is accepting connections{/$}, reloading",
+                        );
+                    }
+                    controller.reload();
+                }
+
+                status = child.wait() => {
+                    match status {
+                        Ok(status) => bunt::eprintln!(
+                            "{$red+bold}✘ Backend exited{/$} ({}), waiting for file changes \
+                                to restart it",
+                            status,
+                        ),
+                        Err(e) => bunt::eprintln!(
+                            "{$red+bold}✘ Failed to wait for backend{/$}: {}",
+                            e,
+                        ),
+                    }
+
+                    if changes_closed || !next_change(&mut changes, &mut shutdown).await {
+                        break 'backend;
+                    }
+                    break;
+                }
+
+                code = shutdown.recv() => {
+                    stop(&mut child).await;
+                    std::process::exit(code);
+                }
+
+                change = changes.recv(), if !changes_closed => {
+                    if change.is_none() {
+                        changes_closed = true;
+                        continue;
+                    }
+
+                    if !muted {
+                        bunt::println!("{$yellow+bold}↻ Files changed{/$}, restarting backend");
+                    }
+                    stop(&mut child).await;
+                    break;
+                }
+            }
+        }
+
+        // Multiple changes might have queued up while stopping the backend,
+        // but we only need to restart it once.
+        while changes.try_recv().is_ok() {}
+    }
+
+    // The backend won't be restarted anymore, but our signal handlers stay
+    // installed for the lifetime of the process, so we still have to exit on
+    // shutdown signals.
+    std::process::exit(shutdown.recv().await);
+}
+
+/// Waits for the next change, returning `false` if there won't be any more.
+/// Exits the process on shutdown signals.
+async fn next_change(changes: &mut UnboundedReceiver<()>, shutdown: &mut Shutdown) -> bool {
+    tokio::select! {
+        change = changes.recv() => change.is_some(),
+        code = shutdown.recv() => std::process::exit(code),
+    }
+}
+
+/// Listens for signals asking penguin to terminate. As the backend and
+/// `--exec` commands run in their own process group, they do not receive them
+/// and have to be stopped by us.
+pub(crate) struct Shutdown {
+    #[cfg(unix)]
+    interrupt: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    terminate: tokio::signal::unix::Signal,
+}
+
+impl Shutdown {
+    pub(crate) fn listen() -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            Self {
+                interrupt: signal(SignalKind::interrupt()).expect("failed to listen for SIGINT"),
+                terminate: signal(SignalKind::terminate()).expect("failed to listen for SIGTERM"),
+            }
+        }
+
+        #[cfg(not(unix))]
+        Self {}
+    }
+
+    /// Waits for a signal and returns the exit code penguin should exit with.
+    pub(crate) async fn recv(&mut self) -> i32 {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = self.interrupt.recv() => 128 + libc::SIGINT,
+            _ = self.terminate.recv() => 128 + libc::SIGTERM,
+        }
+
+        #[cfg(not(unix))]
+        {
+            let _ = tokio::signal::ctrl_c().await;
+            130
+        }
+    }
+}
+
+/// Spawns the backend, forwarding its output with a prefix.
+fn spawn(command: &str) -> std::io::Result<Child> {
+    let mut cmd = shell(command);
+    cmd.stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    // So that we can stop the shell and everything it started (e.g. `cargo
+    // run` and its child).
+    own_process_group(&mut cmd);
+
+    let mut child = cmd.spawn()?;
+    tokio::spawn(forward(child.stdout.take().expect("stdout not piped"), false));
+    tokio::spawn(forward(child.stderr.take().expect("stderr not piped"), true));
+
+    Ok(child)
+}
+
+/// Prints every line from `output` to stdout or stderr, prefixed to make it
+/// distinguishable from penguin's own output.
+async fn forward(output: impl AsyncRead + Unpin, is_stderr: bool) {
+    let mut output = BufReader::new(output);
+    let mut line = Vec::new();
+    while let Ok(n) = output.read_until(b'\n', &mut line).await {
+        if n == 0 {
+            break;
+        }
+
+        let s = String::from_utf8_lossy(&line);
+        let s = s.trim_end_matches(&['\r', '\n'][..]);
+        if is_stderr {
+            bunt::eprintln!("{$dimmed}[backend]{/$} {}", s);
+        } else {
+            bunt::println!("{$dimmed}[backend]{/$} {}", s);
+        }
+        line.clear();
+    }
+}
+
+/// Stops the backend, first asking it politely and killing it if it does not
+/// exit within `SHUTDOWN_TIMEOUT`.
+async fn stop(child: &mut Child) {
+    #[cfg(unix)]
+    if let Some(pid) = child.id() {
+        // SAFETY: `kill` has no memory safety preconditions. The negative PID
+        // addresses the process group created in `spawn`.
+        unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGTERM) };
+        match tokio::time::timeout(SHUTDOWN_TIMEOUT, child.wait()).await {
+            Ok(_) => return,
+            Err(_) => {
+                log::warn!("Backend did not exit within {:?}, killing it", SHUTDOWN_TIMEOUT);
+                unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) };
+            }
+        }
+    }
+
+    if let Err(e) = child.kill().await {
+        log::warn!("Failed to kill backend: {}", e);
+    }
+}
@@ -0,0 +1,323 @@
+//! Loading settings from a `penguin.toml` configuration file.
+
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use log::info;
+use penguin::{Cors, CustomHeader, Mount, ProxyTarget};
+use serde::Deserialize;
+use structopt::{StructOpt, clap::ArgMatches};
+
+use crate::args::{Args, Command, ServeOptions};
+
+
+/// The configuration file that is loaded if it exists in the working directory
+/// and no other file is specified via `--config`.
+pub(crate) const DEFAULT_FILE: &str = "penguin.toml";
+
+/// Contents of the configuration file. Every setting corresponds to a CLI flag.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ConfigFile {
+    bind: Option<IpAddr>,
+    port: Option<u16>,
+    control_path: Option<String>,
+
+    /// Only used by `penguin proxy`.
+    proxy: Option<ProxyTarget>,
+
+    #[serde(default)]
+    mounts: Vec<Mount>,
+
+    #[serde(default)]
+    headers: Vec<CustomHeader>,
+    proxy_headers: Option<bool>,
+
+    /// Overridden by `--cors`.
+    cors: Option<Cors>,
+
+    #[serde(default)]
+    watch: WatchConfig,
+}
+
+/// The `[watch]` section. Durations are in ms.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WatchConfig {
+    #[serde(default)]
+    paths: Vec<PathBuf>,
+    #[serde(default)]
+    ignore: Vec<String>,
+    #[serde(default)]
+    watch_only: Vec<String>,
+    auto: Option<bool>,
+    gitignore: Option<bool>,
+    exec: Option<String>,
+    trigger_file: Option<PathBuf>,
+    debounce: Option<u64>,
+    removal_debounce: Option<u64>,
+    max_wait: Option<u64>,
+    poll: Option<u64>,
+}
+
+/// Loads the file specified via `--config` or, if that's not given, the
+/// default file if it exists, and fills in all settings not given on the
+/// command line. Returns a `Reloader` if a file was loaded.
+pub(crate) fn load(args: &mut Args, matches: &ArgMatches<'static>) -> Result<Option<Reloader>> {
+    let path = match &args.config {
+        Some(path) => path.clone(),
+        None if Path::new(DEFAULT_FILE).is_file() => DEFAULT_FILE.into(),
+        None => return Ok(None),
+    };
+
+    let reloader = Reloader { path, matches: matches.clone() };
+    reloader.apply(args)?;
+    Ok(Some(reloader))
+}
+
+/// Loads the configuration file again, e.g. after it changed.
+#[derive(Clone)]
+pub(crate) struct Reloader {
+    path: PathBuf,
+    /// The original command line.
+    matches: ArgMatches<'static>,
+}
+
+impl Reloader {
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the arguments from the original command line merged with the
+    /// current contents of the file.
+    pub(crate) fn load(&self) -> Result<Args> {
+        let mut args = Args::from_clap(&self.matches);
+        self.apply(&mut args)?;
+        Ok(args)
+    }
+
+    fn apply(&self, args: &mut Args) -> Result<()> {
+        info!("Loading configuration file '{}'", self.path.display());
+        let file = ConfigFile::load(&self.path).with_context(|| {
+            format!("failed to load configuration file '{}'", self.path.display())
+        })?;
+        file.apply(args, &self.matches)
+    }
+}
+
+impl ConfigFile {
+    fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let mut file = toml::from_str::<Self>(&content)?;
+
+        // Relative paths are relative to the configuration file.
+        let base = path.parent().unwrap_or(Path::new(""));
+        for mount in &mut file.mounts {
+            mount.fs_path = base.join(&mount.fs_path);
+        }
+        for path in &mut file.watch.paths {
+            *path = base.join(&*path);
+        }
+        if let Some(path) = &mut file.watch.trigger_file {
+            *path = base.join(&*path);
+        }
+
+        Ok(file)
+    }
+
+    /// Overwrites all values in `args` that were not explicitly given on the
+    /// command line with values from this file. Lists are merged.
+    fn apply(self, args: &mut Args, matches: &ArgMatches) -> Result<()> {
+        // Global arguments might end up in the matches of the subcommand.
+        let given = |name: &str| {
+            matches.occurrences_of(name) > 0
+                || matches.subcommand().1.is_some_and(|m| m.occurrences_of(name) > 0)
+        };
+
+        if let Some(bind) = self.bind.filter(|_| !given("bind")) {
+            args.bind = bind;
+        }
+        if let Some(port) = self.port.filter(|_| !given("port")) {
+            args.port = port;
+        }
+        if args.control_path.is_none() {
+            args.control_path = self.control_path;
+        }
+
+        let (options, root_mounted, run) = match &mut args.cmd {
+            Command::Proxy { target, run, options } => {
+                if target.is_none() {
+                    *target = self.proxy;
+                }
+                (options, false, run.is_some())
+            }
+            Command::Serve { path, options } => (options, path.is_some(), false),
+            Command::Reload => return Ok(()),
+        };
+
+        // Mounts given on the command line override the ones in the file with
+        // the same URI path.
+        for mount in self.mounts {
+            let uri_path = mount.uri_path.trim_matches('/');
+            let overridden = (root_mounted && uri_path.is_empty())
+                || options.mounts.iter().any(|m| m.uri_path.trim_matches('/') == uri_path);
+            if !overridden {
+                options.mounts.push(mount);
+            }
+        }
+
+        // Headers from the command line are added last so that they win.
+        options.headers.splice(0..0, self.headers);
+        if self.proxy_headers == Some(true) {
+            options.proxy_headers = true;
+        }
+        options.cors_config = self.cors;
+
+        self.watch.apply(options, given);
+        if options.exec.is_some() && options.trigger_file.is_some() {
+            bail!("'exec' and 'trigger_file' cannot be used together");
+        }
+        if options.exec.is_some() && run {
+            bail!("'exec' and '--run' cannot be used together");
+        }
+
+        Ok(())
+    }
+}
+
+impl WatchConfig {
+    fn apply(self, options: &mut ServeOptions, given: impl Fn(&str) -> bool) {
+        options.watched_paths.extend(self.paths);
+        options.ignored.extend(self.ignore);
+        options.watch_only.extend(self.watch_only);
+
+        if self.auto == Some(false) {
+            options.no_auto_watch = true;
+        }
+        if self.gitignore == Some(false) {
+            options.no_gitignore = true;
+        }
+        if options.exec.is_none() {
+            options.exec = self.exec;
+        }
+        if options.trigger_file.is_none() {
+            options.trigger_file = self.trigger_file;
+        }
+        if options.poll_interval.is_none() {
+            options.poll_interval = self.poll.map(Duration::from_millis);
+        }
+
+        let durations = [
+            ("debounce-duration", self.debounce, &mut options.debounce_duration),
+            (
+                "removal-debounce-duration",
+                self.removal_debounce,
+                &mut options.removal_debounce_duration,
+            ),
+            ("max-wait", self.max_wait, &mut options.max_wait),
+        ];
+        for (name, ms, duration) in durations {
+            if let Some(ms) = ms.filter(|_| !given(name)) {
+                *duration = Duration::from_millis(ms);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(file: &str, cli: &[&str]) -> Result<Args> {
+        let matches = Args::clap().get_matches_from_safe(cli)?;
+        let mut args = Args::from_clap(&matches);
+        toml::from_str::<ConfigFile>(file)?.apply(&mut args, &matches)?;
+        Ok(args)
+    }
+
+    fn serve_options(args: &Args) -> &ServeOptions {
+        match &args.cmd {
+            Command::Proxy { options, .. } | Command::Serve { options, .. } => options,
+            Command::Reload => panic!("no options"),
+        }
+    }
+
+    #[test]
+    fn merge() {
+        let file = r#"
+            port = 5000
+            bind = "0.0.0.0"
+            control_path = "/penguin"
+            proxy = "localhost:8000"
+
+            [[mounts]]
+            uri_path = "/assets"
+            fs_path = "static"
+
+            [[mounts]]
+            uri_path = "/docs"
+            fs_path = "target/doc"
+            follow_symlinks = true
+
+            [[headers]]
+            route = "*.wasm"
+            name = "Cache-Control"
+            value = "no-store"
+
+            [cors]
+            origins = ["http://localhost:3000"]
+
+            [watch]
+            paths = ["src"]
+            auto = false
+            debounce = 50
+            max_wait = 1000
+        "#;
+
+        let args = apply(file, &["penguin", "proxy", "-p", "3000", "--max-wait", "500"]).unwrap();
+        assert_eq!(args.port, 3000);
+        assert_eq!(args.bind, IpAddr::from([0, 0, 0, 0]));
+        assert_eq!(args.control_path.as_deref(), Some("/penguin"));
+        match &args.cmd {
+            Command::Proxy { target, .. } => {
+                assert_eq!(target.as_ref().unwrap().to_string(), "http://localhost:8000");
+            }
+            _ => panic!("wrong subcommand"),
+        }
+        let options = serve_options(&args);
+        assert_eq!(options.mounts.len(), 2);
+        assert!(options.mounts[1].follow_symlinks);
+        assert_eq!(options.watched_paths, [PathBuf::from("src")]);
+        assert!(options.no_auto_watch);
+        assert_eq!(options.debounce_duration, Duration::from_millis(50));
+        assert_eq!(options.max_wait, Duration::from_millis(500));
+        assert_eq!(options.removal_debounce_duration, Duration::from_millis(3000));
+
+        // CLI values take precedence.
+        let args = apply(file, &[
+            "penguin", "serve", "public", "-m", "assets/:dist", "-w", "tests", "--port", "3000",
+        ]).unwrap();
+        assert_eq!(args.port, 3000);
+        let options = serve_options(&args);
+        assert_eq!(options.mounts.len(), 2);
+        assert_eq!(options.mounts[0].fs_path, PathBuf::from("dist"));
+        assert_eq!(options.mounts[1].uri_path, "/docs");
+        assert_eq!(options.headers[0].route, "*.wasm");
+        let cors = options.cors_config.as_ref().unwrap();
+        assert_eq!(cors.origins, ["http://localhost:3000"]);
+        assert_eq!(cors.methods, ["GET", "HEAD", "POST"]);
+        assert_eq!(options.watched_paths, [PathBuf::from("tests"), PathBuf::from("src")]);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(apply("prot = 3000", &["penguin", "serve", "."]).is_err());
+        assert!(apply("[watch]\nexec = \"make\"", &["penguin", "serve", "--trigger-file", "x"])
+            .is_err());
+    }
+}
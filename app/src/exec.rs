@@ -0,0 +1,177 @@
+use std::process::Stdio;
+
+use penguin::Controller;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    process::Command,
+    sync::mpsc::UnboundedReceiver,
+};
+
+use crate::backend::Shutdown;
+
+
+/// Runs `command` whenever a change arrives via `changes` and reloads all
+/// browser sessions if it succeeded. If it failed, its stderr output is shown
+/// in the browser instead. If a new change arrives while the command is still
+/// running, it is killed and started again. On shutdown signals, the command
+/// is killed and the process exits.
+pub(crate) async fn run_on_change(
+    command: String,
+    mut changes: UnboundedReceiver<()>,
+    controller: Controller,
+    muted: bool,
+) {
+    let mut shutdown = Shutdown::listen();
+    loop {
+        let change = tokio::select! {
+            change = changes.recv() => change,
+            code = shutdown.recv() => std::process::exit(code),
+        };
+        if change.is_none() {
+            break;
+        }
+
+        loop {
+            // Multiple changes might have queued up, but we only need to run
+            // the command once for all of them.
+            while changes.try_recv().is_ok() {}
+
+            if !muted {
+                bunt::println!("{$cyan+bold}▸ Running{/$} {[yellow]}", command);
+            }
+            controller.show_build_indicator();
+
+            tokio::select! {
+                outcome = run(&command) => {
+                    match outcome {
+                        Ok(Ok(())) => {
+                            if !muted {
+                                bunt::println!("{$green+bold}✔ Command succeeded{/$}, reloading");
+                            }
+                            controller.reload();
+                        }
+                        Ok(Err(stderr)) => {
+                            if !muted {
+                                bunt::println!("{$red+bold}✘ Command failed{/$}");
+                            }
+                            controller.show_message(failure_message(&command, &stderr));
+                        }
+                        Err(e) => {
+                            bunt::eprintln!(
+                                "{$red+bold}✘ Failed to run{/$} {[yellow]}: {}",
+                                command,
+                                e,
+                            );
+                            controller.show_message(failure_message(&command, &e.to_string()));
+                        }
+                    }
+                    break;
+                }
+
+                // Dropping the `run` future kills the command and everything
+                // it started. That happens before the branch's handler runs.
+                Some(()) = changes.recv() => {
+                    if !muted {
+                        bunt::println!("{$yellow+bold}↻ Files changed{/$}, restarting command");
+                    }
+                }
+
+                code = shutdown.recv() => std::process::exit(code),
+            }
+        }
+    }
+
+    // No more changes will arrive, but our signal handlers stay installed for
+    // the lifetime of the process, so we still have to exit on shutdown
+    // signals.
+    std::process::exit(shutdown.recv().await);
+}
+
+/// Runs the command to completion, forwarding its stderr. Returns `Ok(Err(_))`
+/// with the stderr output if the command exited unsuccessfully.
+async fn run(command: &str) -> std::io::Result<Result<(), String>> {
+    let mut cmd = shell(command);
+    cmd.stderr(Stdio::piped()).kill_on_drop(true);
+    own_process_group(&mut cmd);
+    let mut child = cmd.spawn()?;
+
+    // Declared after `child` so that it is dropped first, while the shell's
+    // PID (and thus the process group ID) cannot have been reused yet.
+    let mut group = KillGroupOnDrop(child.id());
+
+    let mut stderr = BufReader::new(child.stderr.take().expect("stderr not piped"));
+    let mut collected = String::new();
+    let mut line = Vec::new();
+    while stderr.read_until(b'\n', &mut line).await? > 0 {
+        let s = String::from_utf8_lossy(&line);
+        eprint!("{}", s);
+        collected.push_str(&s);
+        line.clear();
+    }
+
+    let status = child.wait().await?;
+    group.0 = None;
+    Ok(if status.success() { Ok(()) } else { Err(collected) })
+}
+
+/// Kills the process group with the given ID when dropped. `kill_on_drop`
+/// only kills the shell, but not the processes started by it (e.g. `cargo`
+/// and `rustc` for `cargo build`).
+struct KillGroupOnDrop(Option<u32>);
+
+impl Drop for KillGroupOnDrop {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(pgid) = self.0 {
+            // SAFETY: `kill` has no memory safety preconditions. The negative
+            // PID addresses the process group created via `own_process_group`.
+            unsafe { libc::kill(-(pgid as libc::pid_t), libc::SIGKILL) };
+        }
+    }
+}
+
+/// Returns a command that runs `command` in the platform's shell.
+pub(crate) fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut out = Command::new("cmd");
+        out.arg("/C").arg(command);
+        out
+    } else {
+        let mut out = Command::new("sh");
+        out.arg("-c").arg(command);
+        out
+    }
+}
+
+/// Puts the process spawned by `cmd` into its own process group, so that the
+/// shell and everything it started can be stopped together.
+pub(crate) fn own_process_group(cmd: &mut Command) {
+    #[cfg(unix)]
+    // SAFETY: `setpgid` is async-signal-safe, so it may be called between
+    // `fork` and `exec`.
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::setpgid(0, 0) == 0 {
+                Ok(())
+            } else {
+                Err(std::io::Error::last_os_error())
+            }
+        });
+    }
+
+    #[cfg(not(unix))]
+    let _ = cmd;
+}
+
+fn failure_message(command: &str, stderr: &str) -> String {
+    format!(
+        "<h1 style=\"font-family: sans-serif\">Command failed: <code>{}</code></h1>\
+            <pre style=\"white-space: pre-wrap\">{}</pre>",
+        escape_html(command),
+        escape_html(stderr),
+    )
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
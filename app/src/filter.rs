@@ -0,0 +1,265 @@
+use std::{
+    collections::HashMap,
+    env,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::{Match, gitignore::Gitignore};
+
+use crate::args::ServeOptions;
+
+
+/// Paths that are never interesting when watching: VCS internals and
+/// temporary files created by common editors.
+const DEFAULT_IGNORES: &[&str] = &[
+    ".git",
+    ".hg",
+    ".svn",
+    ".DS_Store",
+    "*.swp",
+    "*.swo",
+    "*.swx",
+    "4913",
+    "*~",
+    ".#*",
+    "#*#",
+    "*___jb_tmp___",
+    "*___jb_old___",
+];
+
+/// Decides which changed paths in watched directories should trigger a
+/// reload, based on `--ignore`, `--watch-only` and `.gitignore` files.
+pub(crate) struct PathFilter {
+    /// If set, only changes to exactly this file are relevant.
+    trigger_file: Option<PathBuf>,
+    roots: Vec<PathBuf>,
+    ignored: Globs,
+    watch_only: Option<Globs>,
+    gitignores: Option<Gitignores>,
+}
+
+impl PathFilter {
+    pub(crate) fn new(options: &ServeOptions, roots: &[&Path]) -> Result<Self> {
+        let ignored = DEFAULT_IGNORES.iter()
+            .copied()
+            .chain(options.ignored.iter().map(|s| s.as_str()));
+
+        Ok(Self {
+            trigger_file: None,
+            roots: roots.iter().map(|p| absolute(p)).collect(),
+            ignored: Globs::new(ignored).context("invalid '--ignore' glob")?,
+            watch_only: if options.watch_only.is_empty() {
+                None
+            } else {
+                Some(Globs::new(&options.watch_only).context("invalid '--watch-only' glob")?)
+            },
+            gitignores: if options.no_gitignore { None } else { Some(Gitignores::default()) },
+        })
+    }
+
+    /// Returns a filter only letting through changes to `file`.
+    pub(crate) fn trigger_file(file: &Path) -> Self {
+        Self {
+            trigger_file: Some(absolute(file)),
+            roots: vec![],
+            ignored: Globs::new(None::<&str>).expect("empty glob set is valid"),
+            watch_only: None,
+            gitignores: None,
+        }
+    }
+
+    /// Returns whether a change of `path` should be reacted to.
+    pub(crate) fn is_relevant(&mut self, path: &Path) -> bool {
+        let path = absolute(path);
+        if let Some(trigger_file) = &self.trigger_file {
+            return path == *trigger_file;
+        }
+
+        // If watched paths are nested, the innermost one is used.
+        let root = self.roots.iter()
+            .filter(|root| path.starts_with(root))
+            .max_by_key(|root| root.components().count());
+        let relative = root.and_then(|root| path.strip_prefix(root).ok()).unwrap_or(&path);
+
+        if self.ignored.is_match(relative) {
+            return false;
+        }
+        if self.watch_only.as_ref().is_some_and(|globs| !globs.is_match(relative)) {
+            return false;
+        }
+        let root = root.map(|root| root.as_path());
+        if self.gitignores.as_mut().is_some_and(|gitignores| gitignores.is_ignored(&path, root)) {
+            return false;
+        }
+
+        true
+    }
+}
+
+/// A set of user specified globs.
+struct Globs {
+    /// Globs without `/`, matched against each path component.
+    components: GlobSet,
+
+    /// Globs with `/`, matched against the path relative to the watched root.
+    paths: GlobSet,
+}
+
+impl Globs {
+    fn new(globs: impl IntoIterator<Item = impl AsRef<str>>) -> Result<Self> {
+        let mut components = GlobSetBuilder::new();
+        let mut paths = GlobSetBuilder::new();
+        for glob in globs {
+            let glob = glob.as_ref();
+            let (builder, pattern) = if glob.contains('/') {
+                (&mut paths, glob.trim_start_matches('/').trim_end_matches('/'))
+            } else {
+                (&mut components, glob)
+            };
+
+            let glob = GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()
+                .with_context(|| format!("failed to parse glob '{}'", glob))?;
+            builder.add(glob);
+        }
+
+        Ok(Self {
+            components: components.build()?,
+            paths: paths.build()?,
+        })
+    }
+
+    fn is_match(&self, relative: &Path) -> bool {
+        relative.components().any(|c| self.components.is_match(c.as_os_str()))
+            || relative.ancestors().any(|p| !p.as_os_str().is_empty() && self.paths.is_match(p))
+    }
+}
+
+/// Lazily loaded `.gitignore` files.
+#[derive(Default)]
+struct Gitignores {
+    /// Maps directories to their parsed `.gitignore` file, if they have one.
+    cache: HashMap<PathBuf, Option<Gitignore>>,
+}
+
+impl Gitignores {
+    /// Returns whether `path` is ignored by any `.gitignore` file of the
+    /// repository it is in, following git's precedence rules. Only `path` and
+    /// its parents below `root` are checked: watching a directory that is
+    /// ignored itself (e.g. build output) is intentional.
+    fn is_ignored(&mut self, path: &Path, root: Option<&Path>) -> bool {
+        // Changed `.gitignore` files need to be parsed again.
+        if path.file_name().is_some_and(|name| name == ".gitignore") {
+            if let Some(dir) = path.parent() {
+                self.cache.remove(dir);
+            }
+        }
+
+        // Paths outside of git repositories are not affected by any
+        // `.gitignore`.
+        let Some(repo_root) = path.ancestors().skip(1).find(|dir| dir.join(".git").exists()) else {
+            return false;
+        };
+        let root = root.filter(|root| root.starts_with(repo_root)).unwrap_or(repo_root);
+
+        // Like in git, everything inside an ignored directory is ignored.
+        path.ancestors()
+            .take_while(|p| *p != root && p.starts_with(root))
+            .any(|p| self.matches(p, repo_root))
+    }
+
+    /// Returns whether `path` itself is ignored by a `.gitignore` file.
+    fn matches(&mut self, path: &Path, repo_root: &Path) -> bool {
+        let is_dir = path.is_dir();
+        for dir in path.ancestors().skip(1) {
+            let gitignore = self.cache.entry(dir.to_owned()).or_insert_with(|| {
+                let file = dir.join(".gitignore");
+                if !file.is_file() {
+                    return None;
+                }
+
+                let (gitignore, error) = Gitignore::new(&file);
+                if let Some(e) = error {
+                    log::warn!("Failed to fully parse '{}': {}", file.display(), e);
+                }
+                Some(gitignore)
+            });
+
+            // The innermost `.gitignore` with a matching rule wins.
+            match gitignore.as_ref().map(|gi| gi.matched(path, is_dir)) {
+                Some(Match::Ignore(_)) => return true,
+                Some(Match::Whitelist(_)) => return false,
+                Some(Match::None) | None => {}
+            }
+
+            if dir == repo_root {
+                break;
+            }
+        }
+
+        false
+    }
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    match env::current_dir() {
+        Ok(cwd) if !path.is_absolute() => cwd.join(path),
+        _ => path.to_owned(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::{Globs, Gitignores};
+
+    #[test]
+    fn globs() {
+        let globs = Globs::new([".git", "*.swp", "node_modules", "dist/*.map"]).unwrap();
+        let matches = |p: &str| globs.is_match(Path::new(p));
+
+        assert!(matches(".git/index"));
+        assert!(matches("src/.main.rs.swp"));
+        assert!(matches("web/node_modules/foo/index.js"));
+        assert!(matches("dist/app.js.map"));
+        assert!(matches("dist/app.js.map/inner"));
+
+        assert!(!matches("src/main.rs"));
+        assert!(!matches("src/git/mod.rs"));
+        assert!(!matches("dist/app.js"));
+        assert!(!matches("dist/sub/app.js.map"));
+        assert!(!matches("web/dist/app.js.map"));
+    }
+
+    #[test]
+    fn gitignored_root() {
+        let repo = std::env::temp_dir()
+            .join(format!("penguin-test-{}-gitignore", std::process::id()));
+        let _ = fs::remove_dir_all(&repo);
+        fs::create_dir_all(repo.join(".git")).unwrap();
+        fs::create_dir_all(repo.join("dist/maps")).unwrap();
+        fs::write(repo.join(".gitignore"), "/dist\n*.map\nmaps/\n").unwrap();
+
+        let mut gitignores = Gitignores::default();
+        let dist = repo.join("dist");
+        let mut ignored = |path: &str, root: &Path| {
+            gitignores.is_ignored(&repo.join(path), Some(root))
+        };
+
+        // Changes in the watched, but gitignored `dist` are relevant...
+        assert!(!ignored("dist/index.html", &dist));
+        // ... unless they are ignored by other rules.
+        assert!(ignored("dist/app.js.map", &dist));
+        assert!(ignored("dist/maps/app.js", &dist));
+        // When watching the whole repository, `dist` is ignored.
+        assert!(ignored("dist/index.html", &repo));
+        assert!(!ignored("src/main.rs", &repo));
+
+        fs::remove_dir_all(&repo).unwrap();
+    }
+}
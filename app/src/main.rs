@@ -2,12 +2,16 @@ use std::{env, iter};
 
 use anyhow::{Context, Result};
 use log::LevelFilter;
-use penguin::{Mount, hyper::{Body, Client, Request}};
+use penguin::hyper::{Body, Client, Request};
 use structopt::StructOpt;
 
 use crate::args::{Args, Command};
 
 mod args;
+mod backend;
+mod config;
+mod exec;
+mod filter;
 mod server;
 
 
@@ -42,20 +46,22 @@ async fn run() -> Result<()> {
     }
 
     // Parse CLI arguments.
-    let args = Args::from_args();
+    let matches = Args::clap().get_matches();
+    let mut args = Args::from_clap(&matches);
 
     init_logger(args.log_level);
+    let config_file = config::load(&mut args, &matches)?;
 
     match &args.cmd {
-        Command::Proxy { target, options } => {
-            server::run(Some(target), &options.mounts, options, &args)
+        Command::Proxy { run, options, .. } => {
+            server::run(run.as_deref(), options, &args, config_file)
                 .await
                 .context("failed to run server")?;
         }
-        Command::Serve { path, options } => {
-            let root_mount = path.clone().map(|p| Mount { uri_path: "/".into(), fs_path: p });
-            let mounts = options.mounts.iter().chain(&root_mount);
-            server::run(None, mounts, options, &args).await.context("failed to run server")?;
+        Command::Serve { options, .. } => {
+            server::run(None, options, &args, config_file)
+                .await
+                .context("failed to run server")?;
         }
         Command::Reload => reload(&args).await.context("failed to send reload request")?,
     }
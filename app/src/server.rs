@@ -1,51 +1,120 @@
-use std::{env, ops::Deref, path::Path, thread, time::Duration};
+use std::{env, net::SocketAddr, ops::Deref, path::Path, thread, time::{Duration, Instant}};
 
 use anyhow::{Context, Result};
 use log::{debug, info, trace, LevelFilter};
-use notify::Op;
-use penguin::{Config, Controller, Mount, ProxyTarget, Server};
+use notify::RecursiveMode;
+use penguin::{Config, ConsoleLevel, Controller, Cors, Event, EventStream, Mount, Server};
+use tokio::sync::mpsc;
 
-use crate::args::{Args, DEFAULT_PORT, ServeOptions};
+use crate::{
+    args::{Args, Command, DEFAULT_PORT, ServeOptions},
+    backend,
+    config::Reloader,
+    exec,
+    filter::PathFilter,
+};
 
 
 
 pub(crate) async fn run(
-    proxy: Option<&ProxyTarget>,
-    mounts: impl Clone + IntoIterator<Item = &Mount>,
+    backend: Option<&str>,
     options: &ServeOptions,
     args: &Args,
+    config_file: Option<Reloader>,
 ) -> Result<()> {
-    let bind_addr = (args.bind, args.port).into();
-    let mut builder = Server::bind(bind_addr);
-
-    for mount in mounts.clone() {
-        builder = builder.add_mount(&mount.uri_path, &mount.fs_path)
-            .context("failed to add mount")?;
+    let bind_addr: SocketAddr = (args.bind, args.port).into();
+    let config = config(args)?;
+    let (server, controller) = Server::build(config.clone());
+    if options.forward_console {
+        tokio::spawn(print_console(controller.subscribe_events()));
     }
-    if let Some(control_path) = &args.control_path {
-        builder = builder.set_control_path(control_path);
+    if let Some(reloader) = &config_file {
+        watch_config_file(reloader, controller.clone(), &config, options, args.is_muted())?;
     }
-    if let Some(target) = proxy {
-        builder = builder.proxy(target.clone())
-    }
-
-
-    let config = builder.validate().context("invalid penguin config")?;
-    let (server, controller) = Server::build(config.clone());
-
-    let watched_paths = if !options.no_auto_watch {
-        mounts.into_iter()
-            .map(|m| &*m.fs_path)
-            .chain(options.watched_paths.iter().map(Deref::deref))
-            .collect()
-    } else if !options.watched_paths.is_empty() {
-        options.watched_paths.iter().map(Deref::deref).collect()
+    let proxy = config.proxy();
+
+    // When a command is executed on file changes, browsers are reloaded once
+    // it finishes. Watching the mounts, which are likely written to by the
+    // command, would trigger the command again.
+    let auto_watched = !options.no_auto_watch
+        && options.exec.is_none()
+        && options.trigger_file.is_none();
+    let mut watched_paths: Vec<&Path> = if auto_watched {
+        config.mounts().iter().map(|m| &*m.fs_path).collect()
     } else {
         vec![]
     };
 
-    if !watched_paths.is_empty() {
-        watch(controller, options, &watched_paths)?;
+    // When supervising a backend, the explicitly watched paths are its
+    // sources and changes restart it. Mounts still just trigger a reload.
+    let mut restart_paths = vec![];
+    if backend.is_some() {
+        restart_paths.extend(options.watched_paths.iter().map(Deref::deref));
+    } else if options.trigger_file.is_none() {
+        watched_paths.extend(options.watched_paths.iter().map(Deref::deref));
+    }
+
+    if let (Some(command), Some(target)) = (backend, proxy) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(backend::supervise(
+            command.to_owned(),
+            target.clone(),
+            rx,
+            controller.clone(),
+            args.is_muted(),
+        ));
+        if !restart_paths.is_empty() {
+            let filter = PathFilter::new(options, &restart_paths)?;
+            watch(
+                move || { let _ = tx.send(()); },
+                options,
+                &restart_paths,
+                filter,
+                RecursiveMode::Recursive,
+            )?;
+        }
+    }
+
+    if let Some(command) = &options.exec {
+        if watched_paths.is_empty() {
+            anyhow::bail!("'--exec' requires at least one path to be watched via '--watch'");
+        }
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(exec::run_on_change(command.clone(), rx, controller, args.is_muted()));
+        let filter = PathFilter::new(options, &watched_paths)?;
+        watch(
+            move || { let _ = tx.send(()); },
+            options,
+            &watched_paths,
+            filter,
+            RecursiveMode::Recursive,
+        )?;
+    } else if let Some(file) = &options.trigger_file {
+        // The file might not exist yet, so we watch its directory instead.
+        let dir = parent_dir(file);
+        watch(
+            move || {
+                info!("Reloading browser sessions as the trigger file changed");
+                controller.reload();
+            },
+            options,
+            &[dir],
+            PathFilter::trigger_file(file),
+            RecursiveMode::NonRecursive,
+        )?;
+    } else if !watched_paths.is_empty() {
+        let filter = PathFilter::new(options, &watched_paths)?;
+        watch(
+            move || {
+                info!("Reloading browser sessions due to file changes in watched directories");
+                controller.reload();
+            },
+            options,
+            &watched_paths,
+            filter,
+            RecursiveMode::Recursive,
+        )?;
     }
 
     // Nice output of what is being done
@@ -56,7 +125,7 @@ pub(crate) async fn run(
         );
 
         if !args.is_quiet() {
-            pretty_print_config(&config, args, &watched_paths);
+            pretty_print_config(&config, args, options, &watched_paths, &restart_paths);
         }
     }
 
@@ -88,28 +157,237 @@ pub(crate) async fn run(
     Ok(())
 }
 
-fn watch<'a>(
+/// Builds the server configuration for the `proxy` or `serve` subcommand.
+pub(crate) fn config(args: &Args) -> Result<Config> {
+    let (proxy, root, options) = match &args.cmd {
+        Command::Proxy { target, options, .. } => {
+            let target = target.as_ref().context(
+                "no proxy target specified, neither as argument nor in the configuration file",
+            )?;
+            (Some(target), None, options)
+        }
+        Command::Serve { path, options } => (None, path.as_ref(), options),
+        Command::Reload => unreachable!("'penguin reload' does not start a server"),
+    };
+
+    let mut builder = Server::bind((args.bind, args.port).into());
+    let root_mount = root.map(|p| Mount {
+        uri_path: "/".into(),
+        fs_path: p.clone(),
+        follow_symlinks: false,
+    });
+    for mount in options.mounts.iter().chain(&root_mount) {
+        builder = builder.mount(mount.clone()).context("failed to add mount")?;
+    }
+    for (extension, mime_type) in &options.mime_types {
+        builder = builder.add_mime_type(extension, mime_type);
+    }
+    for header in &options.headers {
+        builder = builder.header(&header.route, &header.name, &header.value);
+    }
+    builder = builder.proxy_headers(options.proxy_headers);
+    if options.cors {
+        builder = builder.cors(Cors::permissive());
+    } else if let Some(cors) = &options.cors_config {
+        builder = builder.cors(cors.clone());
+    }
+    builder = builder.inject_position(options.inject_position);
+    builder = builder.forward_console(options.forward_console);
+    builder = builder.preserve_state(!options.no_preserve_state);
+    builder = builder.sync(options.sync);
+    builder = builder.connection_badge(options.connection_badge);
+    if let Some(control_path) = &args.control_path {
+        builder = builder.set_control_path(control_path);
+    }
+    if let Some(target) = proxy {
+        builder = builder.proxy(target.clone())
+    }
+
+    builder.validate().context("invalid penguin config")
+}
+
+/// Watches the configuration file and updates the server configuration (e.g.
+/// mounts and the proxy target) when it changes. The bind address, port and
+/// settings of the file watchers, like watched paths, only take effect after
+/// restarting penguin, which is pointed out in a warning.
+fn watch_config_file(
+    reloader: &Reloader,
     controller: Controller,
+    initial_config: &Config,
+    options: &ServeOptions,
+    muted: bool,
+) -> Result<()> {
+    let path = reloader.path();
+    let reloader = reloader.clone();
+    let initial_config = initial_config.clone();
+    let initial_options = options.clone();
+    watch(
+        move || match reloader.load().and_then(|args| Ok((config(&args)?, args))) {
+            Ok((config, args)) => {
+                if !muted {
+                    bunt::println!(
+                        "{$yellow+bold}↻ Configuration file changed{/$}, updating and reloading",
+                    );
+                }
+                let options = match &args.cmd {
+                    Command::Proxy { options, .. } | Command::Serve { options, .. } => options,
+                    Command::Reload => unreachable!("'penguin reload' does not start a server"),
+                };
+                let changed = restart_required(
+                    (&initial_config, &initial_options),
+                    (&config, options),
+                );
+                if !changed.is_empty() {
+                    bunt::eprintln!(
+                        "{$yellow+bold}Warning{/$}: restart penguin to apply all changed \
+                            settings: {}",
+                        changed.join(", "),
+                    );
+                }
+
+                controller.update_config(config);
+                controller.reload();
+            }
+            Err(e) => bunt::eprintln!(
+                "{$red+bold}✘ Failed to update configuration{/$}: {:#}",
+                e,
+            ),
+        },
+        options,
+        &[parent_dir(path)],
+        PathFilter::trigger_file(path),
+        RecursiveMode::NonRecursive,
+    )
+}
+
+/// Returns the names of the settings in the configuration file that differ
+/// between `old` and `new` but are only used on startup, i.e. the address the
+/// server listens on and the settings of the file watchers.
+fn restart_required(
+    (old_config, old): (&Config, &ServeOptions),
+    (new_config, new): (&Config, &ServeOptions),
+) -> Vec<&'static str> {
+    let mount_paths = |config: &Config| {
+        config.mounts().iter().map(|m| m.fs_path.clone()).collect::<Vec<_>>()
+    };
+    let auto_watched = !old.no_auto_watch && old.exec.is_none() && old.trigger_file.is_none();
+
+    [
+        ("bind", old_config.bind_addr().ip() != new_config.bind_addr().ip()),
+        ("port", old_config.bind_addr().port() != new_config.bind_addr().port()),
+        ("watching of mounts", auto_watched && mount_paths(old_config) != mount_paths(new_config)),
+        ("watch.paths", old.watched_paths != new.watched_paths),
+        ("watch.ignore", old.ignored != new.ignored),
+        ("watch.watch_only", old.watch_only != new.watch_only),
+        ("watch.auto", old.no_auto_watch != new.no_auto_watch),
+        ("watch.gitignore", old.no_gitignore != new.no_gitignore),
+        ("watch.exec", old.exec != new.exec),
+        ("watch.trigger_file", old.trigger_file != new.trigger_file),
+        ("watch.debounce", old.debounce_duration != new.debounce_duration),
+        (
+            "watch.removal_debounce",
+            old.removal_debounce_duration != new.removal_debounce_duration,
+        ),
+        ("watch.max_wait", old.max_wait != new.max_wait),
+        ("watch.poll", old.poll_interval != new.poll_interval),
+    ]
+        .iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+/// Returns the directory containing `file`, which can be watched even if the
+/// file does not exist.
+fn parent_dir(file: &Path) -> &Path {
+    match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// Prints console messages forwarded from browser sessions.
+async fn print_console(mut events: EventStream) {
+    while let Some(event) = events.recv().await {
+        if let Event::Console { session, level, message } = event {
+            // Don't let browsers mess with the terminal via escape codes.
+            let message = message.replace(
+                |c: char| c.is_control() && c != '\n' && c != '\t',
+                "�",
+            );
+            let prefix = format!("[browser {}]", session);
+            match level {
+                ConsoleLevel::Error => bunt::println!("{[dimmed]} {[red]}", prefix, message),
+                ConsoleLevel::Warn => bunt::println!("{[dimmed]} {[yellow]}", prefix, message),
+                ConsoleLevel::Info => bunt::println!("{[dimmed]} {[cyan]}", prefix, message),
+                ConsoleLevel::Debug => bunt::println!("{[dimmed]} {[dimmed]}", prefix, message),
+                ConsoleLevel::Log => bunt::println!("{[dimmed]} {}", prefix, message),
+            }
+        }
+    }
+}
+
+/// Watches the given paths and calls `on_change` (debounced) when anything
+/// that passes `filter` changes.
+fn watch(
+    on_change: impl Fn() + Send + 'static,
     options: &ServeOptions,
     paths: &[&Path],
+    mut filter: PathFilter,
+    mode: RecursiveMode,
 ) -> Result<()> {
-    use std::sync::mpsc::{channel, RecvTimeoutError};
-    use notify::{RawEvent, RecursiveMode, Watcher};
-
-    /// Helper to format an optional path in a nice way.
-    fn pretty_path(event: &RawEvent) -> String {
-        match &event.path {
-            Some(p) => p.display().to_string(),
-            None =>  "???".into(),
+    use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+    use notify::{
+        Event, EventKind, PollWatcher, RecommendedWatcher, Watcher,
+        event::{ModifyKind, RenameMode},
+    };
+
+    /// Helper to format the paths of an event in a nice way.
+    fn pretty_path(event: &Event) -> String {
+        match &*event.paths {
+            [] => "???".into(),
+            [from, to] if event.kind == EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                format!("{} → {}", from.display(), to.display())
+            }
+            paths => paths.iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        }
+    }
+
+    /// Receives the next event from the watcher, skipping errors.
+    fn recv(
+        rx: &Receiver<notify::Result<Event>>,
+        timeout: Option<Duration>,
+    ) -> Result<Event, RecvTimeoutError> {
+        loop {
+            let result = match timeout {
+                Some(timeout) => rx.recv_timeout(timeout)?,
+                None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected)?,
+            };
+            match result {
+                Ok(event) => return Ok(event),
+                Err(e) => log::warn!("Error while watching files: {}", e),
+            }
         }
     }
 
     // Create an configure watcher.
     let (tx, rx) = channel();
-    let mut watcher = notify::raw_watcher(tx).context("could not create FS watcher")?;
+    let mut watcher: Box<dyn Watcher + Send> = match options.poll_interval {
+        Some(interval) => {
+            let config = notify::Config::default().with_poll_interval(interval);
+            Box::new(PollWatcher::new(tx, config).context("could not create polling FS watcher")?)
+        }
+        None => Box::new(
+            RecommendedWatcher::new(tx, notify::Config::default())
+                .context("could not create FS watcher")?
+        ),
+    };
 
     for path in paths {
-        watcher.watch(path, RecursiveMode::Recursive)
+        watcher.watch(path, mode)
             .context(format!("failed to watch '{}'", path.display()))?;
     }
 
@@ -119,32 +397,51 @@ fn watch<'a>(
     thread::spawn(move || {
         // Move it to the thread to avoid dropping it early.
         let _watcher = watcher;
-        let debounce_duration_of = |event: &RawEvent| {
-            if event.op.as_ref().is_ok_and(|&op| op == Op::REMOVE) {
+        let debounce_duration_of = |event: &Event| {
+            if event.kind.is_remove() {
                 options.removal_debounce_duration
             } else {
                 options.debounce_duration
             }
         };
+        let mut is_relevant = |event: &Event| {
+            // Files being read (e.g. by penguin serving them) are not changes.
+            let relevant = !event.kind.is_access()
+                && (event.paths.is_empty() || event.paths.iter().any(|p| filter.is_relevant(p)));
+            if !relevant {
+                trace!("Ignoring watch-event '{:?}' for '{}'", event.kind, pretty_path(event));
+            }
+            relevant
+        };
+
+        while let Ok(event) = recv(&rx, None) {
+            if !is_relevant(&event) {
+                continue;
+            }
 
-        while let Ok(event) = rx.recv() {
             let mut debounce_duration = debounce_duration_of(&event);
+            let max_deadline = Instant::now() + options.max_wait;
+            let mut deadline = std::cmp::min(Instant::now() + debounce_duration, max_deadline);
 
             debug!(
                 "Received watch-event '{:?}' for '{}'. Debouncing now for {:?}.",
-                event.op,
+                event.kind,
                 pretty_path(&event),
                 debounce_duration,
             );
 
-            // Debounce. We loop forever until no new event arrived for
-            // `debounce_duration`.
+            // Debounce. We loop until no new relevant event arrived for
+            // `debounce_duration`, but at most until `max_deadline`.
             loop {
-                match rx.recv_timeout(debounce_duration) {
+                match recv(&rx, Some(deadline.saturating_duration_since(Instant::now()))) {
                     Ok(event) => {
+                        if !is_relevant(&event) {
+                            continue;
+                        }
+
                         trace!(
                             "Debounce interrupted by '{:?}' of '{}'",
-                            event.op,
+                            event.kind,
                             pretty_path(&event),
                         );
 
@@ -155,22 +452,35 @@ fn watch<'a>(
                             debounce_duration_of(&event),
                             debounce_duration,
                         );
+                        deadline = std::cmp::min(
+                            Instant::now() + debounce_duration,
+                            max_deadline,
+                        );
                     },
-                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Timeout) => {
+                        if deadline == max_deadline {
+                            debug!("Stopped debouncing after {:?}", options.max_wait);
+                        }
+                        break;
+                    }
                     Err(RecvTimeoutError::Disconnected) => return,
                 }
             }
 
-            // Finally, send a reload command
-            info!("Reloading browser sessions due to file changes in watched directories");
-            controller.reload();
+            on_change();
         }
     });
 
     Ok(())
 }
 
-fn pretty_print_config(config: &Config, args: &Args, watched_paths: &[&Path]) {
+fn pretty_print_config(
+    config: &Config,
+    args: &Args,
+    options: &ServeOptions,
+    watched_paths: &[&Path],
+    restart_paths: &[&Path],
+) {
     // Routing description
     println!();
     bunt::println!("   {$cyan+bold}▸ Routing:{/$}");
@@ -200,11 +510,33 @@ fn pretty_print_config(config: &Config, args: &Args, watched_paths: &[&Path]) {
 
     if !watched_paths.is_empty() {
         println!();
-        bunt::println!("   {$cyan+bold}▸ Watching:{/$} {$dimmed}(reloading on file change){/$}");
-        for p in watched_paths {
-            let canonical = p.canonicalize();
-            bunt::println!("     • {[green]}", canonical.as_deref().unwrap_or(p).display());
+        match &options.exec {
+            None => bunt::println!(
+                "   {$cyan+bold}▸ Watching:{/$} {$dimmed}(reloading on file change){/$}",
+            ),
+            Some(command) => bunt::println!(
+                "   {$cyan+bold}▸ Watching:{/$} {$dimmed}(running{/$} {[yellow]} \
+                    {$dimmed}on file change, then reloading){/$}",
+                command,
+            ),
         }
+        print_paths(watched_paths);
+    }
+
+    if let Some(file) = &options.trigger_file {
+        println!();
+        bunt::println!(
+            "   {$cyan+bold}▸ Watching:{/$} {$dimmed}(reloading when this file changes){/$}",
+        );
+        print_paths(&[file]);
+    }
+
+    if !restart_paths.is_empty() {
+        println!();
+        bunt::println!(
+            "   {$cyan+bold}▸ Watching:{/$} {$dimmed}(restarting backend on file change){/$}",
+        );
+        print_paths(restart_paths);
     }
 
     // Random hints
@@ -226,3 +558,10 @@ fn pretty_print_config(config: &Config, args: &Args, watched_paths: &[&Path]) {
 
     println!();
 }
+
+fn print_paths(paths: &[&Path]) {
+    for p in paths {
+        let canonical = p.canonicalize();
+        bunt::println!("     • {[green]}", canonical.as_deref().unwrap_or(p).display());
+    }
+}
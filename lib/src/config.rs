@@ -1,6 +1,6 @@
-use std::{fmt, net::{IpAddr, SocketAddr}, path::PathBuf, str::FromStr};
+use std::{collections::HashMap, fmt, net::{IpAddr, SocketAddr}, path::PathBuf, str::FromStr};
 
-use hyper::{Uri, http::uri};
+use hyper::{Method, Uri, header::{HeaderName, HeaderValue}, http::uri};
 
 use crate::{Controller, Server};
 
@@ -19,7 +19,14 @@ pub const DEFAULT_CONTROL_PATH: &str = "/~~penguin";
 ///
 /// To create a configuration, use [`Server::bind`] to obtain a [`Builder`]
 /// which can be turned into a `Config`.
+///
+/// With the `serde` feature, this can also be deserialized. All fields are
+/// optional: `bind_addr` defaults to `127.0.0.1:4090`, all others to the same
+/// values as the builder methods. The configuration is validated like in
+/// [`Builder::validate`].
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(remote = "Self", default = "Config::serde_default"))]
 pub struct Config {
     /// The port/socket address the server should be listening on.
     pub(crate) bind_addr: SocketAddr,
@@ -38,9 +45,50 @@ pub struct Config {
     ///
     /// Has to start with `/` and *not* include the trailing `/`.
     pub(crate) control_path: String,
+
+    /// MIME types for file extensions (lowercase, without leading `.`) that
+    /// override the guessed `Content-Type` of files served by the file server.
+    pub(crate) mime_types: HashMap<String, String>,
+
+    /// Where in HTML documents our script is injected.
+    pub(crate) inject_position: InjectPosition,
+
+    /// Value of the `integrity` attribute of the injected `<script>` tag.
+    pub(crate) script_integrity: Option<String>,
+
+    /// Value of the `crossorigin` attribute of the injected `<script>` tag.
+    pub(crate) script_crossorigin: Option<CrossOrigin>,
+
+    /// Whether the browser's console output and uncaught errors are sent to
+    /// the server.
+    pub(crate) forward_console: bool,
+
+    /// Whether scroll position, focus and form fields are restored after
+    /// reloads triggered by penguin.
+    pub(crate) preserve_state: bool,
+
+    /// Whether navigation, scrolling and clicks are mirrored between all
+    /// browser sessions.
+    pub(crate) sync: bool,
+
+    /// Whether a badge showing the connection state is shown on the page.
+    pub(crate) connection_badge: bool,
+
+    /// Headers added to responses of the file server.
+    pub(crate) headers: Vec<CustomHeader>,
+
+    /// Whether `headers` are also added to responses from the proxy.
+    pub(crate) proxy_headers: bool,
+
+    /// If set, cross-origin requests are handled according to these settings.
+    pub(crate) cors: Option<Cors>,
 }
 
 impl Config {
+    pub fn bind_addr(&self) -> SocketAddr {
+        self.bind_addr
+    }
+
     pub fn proxy(&self) -> Option<&ProxyTarget> {
         self.proxy.as_ref()
     }
@@ -52,6 +100,87 @@ impl Config {
     pub fn control_path(&self) -> &str {
         &self.control_path
     }
+
+    pub fn mime_types(&self) -> &HashMap<String, String> {
+        &self.mime_types
+    }
+
+    pub fn inject_position(&self) -> InjectPosition {
+        self.inject_position
+    }
+
+    pub fn script_integrity(&self) -> Option<&str> {
+        self.script_integrity.as_deref()
+    }
+
+    pub fn script_crossorigin(&self) -> Option<CrossOrigin> {
+        self.script_crossorigin
+    }
+
+    pub fn forward_console(&self) -> bool {
+        self.forward_console
+    }
+
+    pub fn preserve_state(&self) -> bool {
+        self.preserve_state
+    }
+
+    pub fn sync(&self) -> bool {
+        self.sync
+    }
+
+    pub fn connection_badge(&self) -> bool {
+        self.connection_badge
+    }
+
+    pub fn headers(&self) -> &[CustomHeader] {
+        &self.headers
+    }
+
+    pub fn proxy_headers(&self) -> bool {
+        self.proxy_headers
+    }
+
+    pub fn cors(&self) -> Option<&Cors> {
+        self.cors.as_ref()
+    }
+
+    #[cfg(feature = "serde")]
+    fn serde_default() -> Self {
+        Builder::new(([127, 0, 0, 1], 4090).into()).0
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Config {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Calls the derived method.
+        Config::serialize(self, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Config {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        // Calls the derived method. The result still has to be normalized and
+        // validated, which the builder does for us.
+        let mut raw = Config::deserialize(deserializer)?;
+        let mounts = std::mem::take(&mut raw.mounts);
+        let mime_types = std::mem::take(&mut raw.mime_types);
+        let control_path = std::mem::take(&mut raw.control_path);
+
+        let mut builder = Builder(raw).set_control_path(control_path);
+        for mount in mounts {
+            builder = builder.mount(mount).map_err(D::Error::custom)?;
+        }
+        for (extension, mime_type) in mime_types {
+            builder = builder.add_mime_type(extension, mime_type);
+        }
+
+        builder.validate().map_err(D::Error::custom)
+    }
 }
 
 /// Builder for the configuration of `Server`.
@@ -67,6 +196,17 @@ impl Builder {
             proxy: None,
             control_path: DEFAULT_CONTROL_PATH.into(),
             mounts: Vec::new(),
+            mime_types: HashMap::new(),
+            inject_position: InjectPosition::BodyEnd,
+            script_integrity: None,
+            script_crossorigin: None,
+            forward_console: false,
+            preserve_state: true,
+            sync: false,
+            connection_badge: false,
+            headers: Vec::new(),
+            proxy_headers: false,
+            cors: None,
         })
     }
 
@@ -97,22 +237,31 @@ impl Builder {
     /// This method returns `ConfigError::DuplicateUriPath` if the same
     /// `uri_path` was added before.
     pub fn add_mount(
-        mut self,
+        self,
         uri_path: impl Into<String>,
         fs_path: impl Into<PathBuf>,
     ) -> Result<Self, ConfigError> {
-        let mut uri_path = uri_path.into();
-        normalize_path(&mut uri_path);
+        self.mount(Mount {
+            uri_path: uri_path.into(),
+            fs_path: fs_path.into(),
+            follow_symlinks: false,
+        })
+    }
 
-        if self.0.mounts.iter().any(|other| other.uri_path == uri_path) {
-            return Err(ConfigError::DuplicateUriPath(uri_path));
-        }
+    /// Like [`Builder::add_mount`], but takes a full [`Mount`], allowing you
+    /// to set per-mount options like [`Mount::follow_symlinks`]. The URI path
+    /// of the mount is normalized.
+    ///
+    /// This method returns `ConfigError::DuplicateUriPath` if the same
+    /// `uri_path` was added before.
+    pub fn mount(mut self, mut mount: Mount) -> Result<Self, ConfigError> {
+        normalize_path(&mut mount.uri_path);
 
-        self.0.mounts.push(Mount {
-            uri_path,
-            fs_path: fs_path.into(),
-        });
+        if self.0.mounts.iter().any(|other| other.uri_path == mount.uri_path) {
+            return Err(ConfigError::DuplicateUriPath(mount.uri_path));
+        }
 
+        self.0.mounts.push(mount);
         Ok(self)
     }
 
@@ -126,6 +275,129 @@ impl Builder {
         self
     }
 
+    /// Sets the MIME type used as `Content-Type` for files with the given
+    /// extension (e.g. `"glb"` -> `"model/gltf-binary"`) served by the file
+    /// server. The extension is case insensitive and may be given with or
+    /// without leading `.`. Calling this again for the same extension
+    /// overwrites the previous value.
+    ///
+    /// Without override, the MIME type is guessed from the extension and, if
+    /// that fails, from the file contents. Textual types get a `charset=utf-8`
+    /// parameter unless they already specify a charset.
+    pub fn add_mime_type(
+        mut self,
+        extension: impl AsRef<str>,
+        mime_type: impl Into<String>,
+    ) -> Self {
+        let extension = extension.as_ref().trim_start_matches('.').to_lowercase();
+        self.0.mime_types.insert(extension, mime_type.into());
+        self
+    }
+
+    /// Sets where the penguin script is injected into HTML documents. Default:
+    /// [`InjectPosition::BodyEnd`].
+    pub fn inject_position(mut self, position: InjectPosition) -> Self {
+        self.0.inject_position = position;
+        self
+    }
+
+    /// Sets the `integrity` attribute of the injected `<script>` tag, e.g.
+    /// `"sha384-..."`. Only useful if your pages require subresource integrity
+    /// for all scripts.
+    ///
+    /// If the proxy response has a `Content-Security-Policy` header containing
+    /// a nonce for scripts, that nonce is added to the `<script>` tag
+    /// automatically.
+    pub fn script_integrity(mut self, integrity: impl Into<String>) -> Self {
+        self.0.script_integrity = Some(integrity.into());
+        self
+    }
+
+    /// Sets the `crossorigin` attribute of the injected `<script>` tag.
+    pub fn script_crossorigin(mut self, crossorigin: CrossOrigin) -> Self {
+        self.0.script_crossorigin = Some(crossorigin);
+        self
+    }
+
+    /// Enables forwarding the browser's console output (`console.log` and
+    /// friends) and uncaught errors to the server. They are available as
+    /// [`Event::Console`][crate::Event::Console] via
+    /// [`Controller::subscribe_events`]. Default: disabled.
+    pub fn forward_console(mut self, enabled: bool) -> Self {
+        self.0.forward_console = enabled;
+        self
+    }
+
+    /// Sets whether the scroll position, the focused element and the values
+    /// of form fields are saved before a reload triggered by penguin and
+    /// restored afterwards. The state is stored in the `sessionStorage` of
+    /// the page. Default: enabled.
+    pub fn preserve_state(mut self, enabled: bool) -> Self {
+        self.0.preserve_state = enabled;
+        self
+    }
+
+    /// Enables sync mode: navigating to another page, scrolling and clicking
+    /// in one browser session is mirrored in all other sessions. Useful to
+    /// test a page on multiple devices at the same time. Scroll positions are
+    /// relative to the page height to account for different screen sizes.
+    /// Default: disabled.
+    pub fn sync(mut self, enabled: bool) -> Self {
+        self.0.sync = enabled;
+        self
+    }
+
+    /// Enables a small badge in the corner of the page showing the state of
+    /// the connection to the penguin server: connected, reconnecting or
+    /// server down. Default: disabled.
+    pub fn connection_badge(mut self, enabled: bool) -> Self {
+        self.0.connection_badge = enabled;
+        self
+    }
+
+    /// Adds the header `name: value` to all responses of the file server for
+    /// requests whose path matches `route`. The pattern has to match the
+    /// whole path (without query), but may contain `*` which matches any
+    /// sequence of characters, e.g. `/assets/*`, `*.wasm` or `*` for all
+    /// requests. Useful to reproduce production headers like
+    /// `Cross-Origin-Opener-Policy` locally.
+    ///
+    /// Headers with the same name that the response already has are
+    /// replaced. If multiple added headers with the same name match a
+    /// request, the one added last wins. Name and value are checked in
+    /// [`Builder::validate`].
+    pub fn header(
+        mut self,
+        route: impl Into<String>,
+        name: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Self {
+        self.0.headers.push(CustomHeader {
+            route: route.into(),
+            name: name.into(),
+            value: value.into(),
+        });
+        self
+    }
+
+    /// Sets whether headers added via [`Builder::header`] are also added to
+    /// responses from the proxy. Default: disabled.
+    pub fn proxy_headers(mut self, enabled: bool) -> Self {
+        self.0.proxy_headers = enabled;
+        self
+    }
+
+    /// Enables CORS handling: preflight requests (`OPTIONS` with an `Origin`
+    /// and `Access-Control-Request-Method` header) to the file server or
+    /// proxy are answered by penguin and `Access-Control-*` headers are added
+    /// to the responses of the file server and the proxy for requests from
+    /// allowed origins. Use [`Cors::permissive`] to allow everything.
+    /// Default: disabled.
+    pub fn cors(mut self, cors: Cors) -> Self {
+        self.0.cors = Some(cors);
+        self
+    }
+
     /// Validates the configuration and builds the server and controller from
     /// it. This is a shortcut for [`Builder::validate`] plus [`Server::build`].
     pub fn build(self) -> Result<(Server, Controller), ConfigError> {
@@ -142,6 +414,40 @@ impl Builder {
             return Err(ConfigError::ProxyAndRootMount);
         }
 
+        for (extension, mime_type) in &self.0.mime_types {
+            if mime_type.parse::<mime_guess::Mime>().is_err() {
+                return Err(ConfigError::InvalidMimeType {
+                    extension: extension.clone(),
+                    mime_type: mime_type.clone(),
+                });
+            }
+        }
+
+        for header in &self.0.headers {
+            if HeaderName::from_bytes(header.name.as_bytes()).is_err()
+                || HeaderValue::from_str(&header.value).is_err()
+            {
+                return Err(ConfigError::InvalidHeader {
+                    name: header.name.clone(),
+                    value: header.value.clone(),
+                });
+            }
+        }
+
+        if let Some(cors) = &self.0.cors {
+            let invalid = cors.origins.iter().find(|o| HeaderValue::from_str(o).is_err())
+                .or_else(|| cors.methods.iter().find(|m| m.parse::<Method>().is_err()))
+                .or_else(|| cors.headers.iter().find(|h| {
+                    *h != "*" && HeaderName::from_bytes(h.as_bytes()).is_err()
+                }));
+            if let Some(value) = invalid {
+                return Err(ConfigError::InvalidCorsValue(value.clone()));
+            }
+            if cors.credentials && cors.origins.iter().any(|o| o == "*") {
+                return Err(ConfigError::CorsCredentialsForAllOrigins);
+            }
+        }
+
         Ok(self.0)
     }
 }
@@ -169,6 +475,96 @@ pub enum ConfigError {
     #[error("neither a proxy nor a mount was specified: server would always \
         respond 404 in this case")]
     NoProxyOrMount,
+
+    #[error("invalid MIME type '{mime_type}' specified for extension '{extension}'")]
+    InvalidMimeType {
+        extension: String,
+        mime_type: String,
+    },
+
+    #[error("invalid header '{name}: {value}'")]
+    InvalidHeader {
+        name: String,
+        value: String,
+    },
+
+    #[error("invalid value '{0}' in CORS configuration")]
+    InvalidCorsValue(String),
+
+    #[error("CORS credentials can only be allowed for an explicit list of origins, not '*'")]
+    CorsCredentialsForAllOrigins,
+}
+
+/// A header added to responses for requests matching a route, see
+/// [`Builder::header`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CustomHeader {
+    /// Pattern matched against the request path, `*` matching any sequence of
+    /// characters.
+    pub route: String,
+
+    /// Header name, e.g. `Cross-Origin-Embedder-Policy`.
+    pub name: String,
+
+    /// Header value, e.g. `require-corp`.
+    pub value: String,
+}
+
+impl CustomHeader {
+    /// Returns whether this header should be added to the response for a
+    /// request to `path`.
+    pub(crate) fn matches(&self, path: &str) -> bool {
+        crate::session::wildcard_match(&self.route, path)
+    }
+}
+
+/// CORS settings, see [`Builder::cors`].
+///
+/// `Cors::default()` allows no origins, the methods `GET`, `HEAD` and `POST`
+/// and no additional request headers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct Cors {
+    /// Origins that are allowed to access penguin, e.g.
+    /// `http://localhost:3000`. `*` allows all origins.
+    pub origins: Vec<String>,
+
+    /// Methods allowed for cross-origin requests. `*` allows all methods.
+    pub methods: Vec<String>,
+
+    /// Request headers allowed for cross-origin requests, in addition to the
+    /// ones that are always allowed by browsers. `*` allows all headers.
+    pub headers: Vec<String>,
+
+    /// Whether requests may include credentials like cookies
+    /// (`Access-Control-Allow-Credentials`). Can only be enabled if `origins`
+    /// does not contain `*`.
+    pub credentials: bool,
+}
+
+impl Cors {
+    /// Allows all origins, methods and headers, but no credentials.
+    pub fn permissive() -> Self {
+        Self {
+            origins: vec!["*".into()],
+            methods: vec!["*".into()],
+            headers: vec!["*".into()],
+            credentials: false,
+        }
+    }
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self {
+            origins: vec![],
+            methods: vec!["GET".into(), "HEAD".into(), "POST".into()],
+            headers: vec![],
+            credentials: false,
+        }
+    }
 }
 
 /// Defintion of a proxy target consisting of a scheme and authority (≈host).
@@ -198,6 +594,22 @@ impl fmt::Display for ProxyTarget {
     }
 }
 
+#[cfg(feature = "serde")]
+impl serde::Serialize for ProxyTarget {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Deserialized from a string via the `FromStr` impl.
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for ProxyTarget {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
 impl FromStr for ProxyTarget {
     type Err = ProxyTargetParseError;
     fn from_str(src: &str) -> Result<Self, Self::Err> {
@@ -250,6 +662,7 @@ pub enum ProxyTargetParseError {
 
 /// A mapping from URI path to file system path.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Mount {
     /// Path prefix of the URI that will map to the directory. Has to start with
     /// `/` and *not* include the trailing `/`.
@@ -258,4 +671,56 @@ pub struct Mount {
     /// Path to a directory on the file system that is served under the
     /// specified URI path.
     pub fs_path: PathBuf,
+
+    /// Whether symlinks pointing outside of `fs_path` are followed.
+    ///
+    /// By default (`false`), requests resolving to a file outside of `fs_path`
+    /// are rejected, which also rejects symlinks pointing outside. That is
+    /// inconvenient for directories containing symlinks into other places
+    /// (e.g. `node_modules` created by pnpm or Nix store paths). If set to
+    /// `true`, those symlinks are followed. Requests with `..` components in
+    /// their path are still rejected in that case.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub follow_symlinks: bool,
+}
+
+/// Where the penguin script is injected into HTML documents (served files and
+/// proxied responses).
+///
+/// If the document does not contain the required tags (e.g. no `<head>`),
+/// penguin falls back to a different location. Markup inside of comments,
+/// `CDATA` sections and `<script>`/`<style>` elements is ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum InjectPosition {
+    /// Right after the opening `<head>` tag, i.e. before all other scripts.
+    HeadStart,
+
+    /// Right before the closing `</head>` tag.
+    HeadEnd,
+
+    /// Right before the closing `</body>` tag. This is the default.
+    BodyEnd,
+}
+
+/// Value of the `crossorigin` attribute of the injected `<script>` tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum CrossOrigin {
+    /// `crossorigin="anonymous"`
+    Anonymous,
+
+    /// `crossorigin="use-credentials"`
+    UseCredentials,
+}
+
+impl CrossOrigin {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Anonymous => "anonymous",
+            Self::UseCredentials => "use-credentials",
+        }
+    }
 }
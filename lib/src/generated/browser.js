@@ -6,95 +6,797 @@
 // Configuration dependent values that are passed/interpolated by the penguin
 // server.
 const control_path = "{{ control_path }}";
-// The target URI of the websocket connection.
+const forward_console = JSON.parse("{{ forward_console }}");
+const preserve_state = JSON.parse("{{ preserve_state }}");
+const sync = JSON.parse("{{ sync }}");
+const connection_badge = JSON.parse("{{ connection_badge }}");
+// Version of the JSON protocol spoken with the server.
+const PROTOCOL_VERSION = 1;
+// The commands this client understands, announced to the server.
+const CAPABILITIES = [
+    "reload",
+    "message",
+    "error",
+    "clear",
+    "building",
+    "sync",
+];
+// The target URI of the websocket connection. The URL of this page is passed
+// along so that the server can address sessions by URL.
 const wsUri = (() => {
-    const scheme = window.location.protocol === "https" ? "wss" : "ws";
+    const scheme = window.location.protocol === "https:" ? "wss" : "ws";
     const host = window.location.host;
-    return `${scheme}://${host}${control_path}`;
+    const url = encodeURIComponent(window.location.href);
+    return `${scheme}://${host}${control_path}?url=${url}&protocol=${PROTOCOL_VERSION}`;
 })();
-// Open websocket connection and install handlers.
-const socket = new WebSocket(wsUri);
-socket.addEventListener("close", onConnectionError);
-socket.addEventListener("open", () => {
-    socket.removeEventListener("close", onConnectionError);
-    socket.addEventListener("close", () => {
-        console.log("penguin server closed WS connection: trying to reconnect...");
-        tryReconnect();
-    });
-    socket.addEventListener("message", onMessage);
+// Reconnection attempts are delayed exponentially, starting with roughly the
+// base delay and growing up to the maximum delay.
+const RECONNECT_BASE_DELAY = 500;
+const RECONNECT_MAX_DELAY = 30000;
+// After this many failed reconnection attempts, the server is considered down.
+const ATTEMPTS_UNTIL_DOWN = 5;
+// Number of failed connection attempts since the connection was lost.
+let reconnectAttempts = 0;
+let reconnectTimeout = null;
+// Build ID sent by the server in its hello message. If it changed after
+// reconnecting, we missed a reload or the server was restarted.
+let buildId = null;
+// Badge showing the connection state, if enabled.
+let connectionBadge = null;
+let transport = "ws";
+let socket = null;
+// ID of this session and the token proving that it's ours, sent by the server
+// in its hello message. Needed to poll and send messages when not using
+// WebSockets.
+let sessionId = null;
+let sessionToken = null;
+connect();
+// If the page becomes visible again, we don't want to wait for the next
+// reconnection attempt.
+document.addEventListener("visibilitychange", () => {
+    if (document.visibilityState === "visible" && reconnectTimeout !== null) {
+        clearTimeout(reconnectTimeout);
+        reconnectTimeout = null;
+        connect();
+    }
 });
-function tryReconnect() {
-    const DELAY_BETWEEN_RETRIES = 2000;
-    const RETRY_COUNT_BEFORE_GIVING_UP = 30;
-    function connect(unregister) {
-        const socket = new WebSocket(wsUri);
-        socket.addEventListener("open", () => {
-            console.log("Reestablished connection: reloading...");
-            unregister();
-            location.reload();
-        });
-    }
-    function retryRegularlyForAWhile() {
-        let count = 0;
-        const interval = setInterval(() => {
-            connect(() => clearInterval(interval));
-            count += 1;
-            if (count > RETRY_COUNT_BEFORE_GIVING_UP) {
-                clearInterval(interval);
-            }
-        }, DELAY_BETWEEN_RETRIES);
-    }
-    // We immediately start trying to reconnect in a loop, but stop after a
-    // while to not waste system resources. But we also check for visibility
-    // changes. Whenever the page visibility changes to "visible", we
-    // immediately retry and also start the retry loop again.
-    retryRegularlyForAWhile();
-    const onVisibilityChange = () => {
-        if (document.visibilityState === "visible") {
-            connect(() => document.removeEventListener("visibilitychange", onVisibilityChange));
-            retryRegularlyForAWhile();
+// Messages to the server sent before the connection was established.
+let pendingMessages = [];
+if (forward_console) {
+    hookConsole();
+}
+reportUrlChanges();
+// Key in `sessionStorage` under which the page state is saved before reloads.
+const STATE_KEY = "penguin-state";
+if (preserve_state) {
+    if (document.readyState === "loading") {
+        document.addEventListener("DOMContentLoaded", restoreState);
+    } else {
+        restoreState();
+    }
+}
+// Key in `sessionStorage` under which the URL is saved that this session was
+// navigated to by sync mode.
+const SYNC_NAVIGATION_KEY = "penguin-sync-navigation";
+// Scroll events before this time are caused by sync mode and not relayed.
+let ignoreScrollUntil = 0;
+if (sync) {
+    initSync();
+}
+// Connects to the server using the current transport.
+function connect() {
+    if (transport === "ws") {
+        socket = connectWs();
+    } else if (transport === "sse") {
+        connectSse();
+    } else {
+        poll();
+    }
+}
+// Switches to the next transport after the current one could not connect.
+function fallBack() {
+    if (transport === "ws" && typeof EventSource !== "undefined") {
+        console.warn("Could not connect to penguin server via WebSocket: using server-sent events");
+        transport = "sse";
+    } else {
+        console.warn("Could not connect to penguin server: using long-polling");
+        transport = "poll";
+    }
+    connect();
+}
+// Called whenever the connection to the server is (re)established.
+function onConnected() {
+    reconnectAttempts = 0;
+    setConnectionState("connected");
+}
+// Opens a websocket connection to the server and installs handlers.
+function connectWs() {
+    const ws = new WebSocket(wsUri);
+    let isOpen = false;
+    ws.addEventListener("open", () => {
+        isOpen = true;
+        onConnected();
+        ws.send(JSON.stringify({
+            type: "hello",
+            protocol: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES,
+        }));
+        flushPendingMessages();
+    });
+    ws.addEventListener("message", onMessage);
+    ws.addEventListener("close", () => {
+        if (isOpen) {
+            console.log("penguin server closed WS connection: trying to reconnect...");
+        } else if (buildId === null) {
+            // We never connected, so WebSockets are probably blocked.
+            fallBack();
+            return;
         }
-    };
-    document.addEventListener("visibilitychange", onVisibilityChange);
+        scheduleReconnect();
+    });
+    return ws;
+}
+// Opens an `EventSource` receiving commands from the server.
+function connectSse() {
+    const source = new EventSource(httpUri("events"));
+    let isOpen = false;
+    source.addEventListener("open", () => {
+        isOpen = true;
+        onConnected();
+    });
+    source.addEventListener("message", onMessage);
+    source.addEventListener("error", () => {
+        // The browser would retry by itself, but without backoff and without
+        // us counting the attempts. So we reconnect like for WebSockets.
+        source.close();
+        if (isOpen) {
+            console.log("penguin server closed SSE connection: trying to reconnect...");
+        } else if (buildId === null) {
+            fallBack();
+            return;
+        }
+        scheduleReconnect();
+    });
+}
+// Polls the server for commands until the connection fails.
+function poll() {
+    const session = sessionId === null ? "" : `&session=${sessionId}&token=${sessionToken}`;
+    fetch(httpUri("poll") + session).then(readPollResponse).then(commands => {
+        onConnected();
+        commands.forEach(handleCommand);
+        poll();
+    }, error => {
+        if (reconnectAttempts === 0) {
+            console.warn("Failed to poll penguin server: trying to reconnect...", error);
+        }
+        scheduleReconnect();
+    });
+}
+function readPollResponse(response) {
+    // The server forgot about this session, e.g. because it was restarted. We
+    // start over with a new one.
+    if (response.status === 404) {
+        sessionId = null;
+        sessionToken = null;
+        return Promise.resolve([]);
+    }
+    if (!response.ok) {
+        return Promise.reject(new Error(`unexpected status ${response.status}`));
+    }
+    return response.json();
+}
+// Returns the URL of an HTTP endpoint under the control path.
+function httpUri(endpoint) {
+    return `${control_path}/${endpoint}?url=${encodeURIComponent(window.location.href)}`;
 }
-function onConnectionError() {
-    console.warn(`Could not connect to web socket backend ${wsUri}`);
+// Tries to reconnect after a delay with exponential backoff. The jitter avoids
+// all tabs reconnecting at the same time when the server comes back.
+function scheduleReconnect() {
+    const exponential = RECONNECT_BASE_DELAY * Math.pow(2, reconnectAttempts);
+    const max = Math.min(RECONNECT_MAX_DELAY, exponential);
+    const delay = max / 2 + Math.random() * max / 2;
+    reconnectAttempts += 1;
+    setConnectionState(reconnectAttempts > ATTEMPTS_UNTIL_DOWN ? "down" : "reconnecting");
+    reconnectTimeout = window.setTimeout(() => {
+        reconnectTimeout = null;
+        connect();
+    }, delay);
+}
+// Updates the connection badge, if enabled.
+function setConnectionState(state) {
+    if (!connection_badge) {
+        return;
+    }
+    if (pageRoot() === null) {
+        document.addEventListener("DOMContentLoaded", () => setConnectionState(state));
+        return;
+    }
+    if (connectionBadge === null) {
+        connectionBadge = createElement("div");
+        connectionBadge.style.position = "fixed";
+        connectionBadge.style.zIndex = "987654322";
+        connectionBadge.style.bottom = "16px";
+        connectionBadge.style.left = "16px";
+        connectionBadge.style.padding = "2px 8px";
+        connectionBadge.style.borderRadius = "4px";
+        connectionBadge.style.fontFamily = "sans-serif";
+        connectionBadge.style.fontSize = "12px";
+        connectionBadge.style.color = "white";
+        connectionBadge.style.opacity = "0.8";
+        connectionBadge.style.pointerEvents = "none";
+        addToPage(connectionBadge, false);
+    }
+    // We encode '…' as escape code to make this work with non-UTF8 HTML.
+    if (state === "connected") {
+        connectionBadge.innerText = "penguin: connected";
+        connectionBadge.style.backgroundColor = "#1e8449";
+    } else if (state === "reconnecting") {
+        connectionBadge.innerText = "penguin: reconnecting\u2026";
+        connectionBadge.style.backgroundColor = "#b9770e";
+    } else {
+        connectionBadge.innerText = "penguin: server down";
+        connectionBadge.style.backgroundColor = "#c0392b";
+    }
 }
 function onMessage(event) {
     if (typeof event.data !== 'string') {
         throw new Error("unexpected WS message from penguin");
     }
-    const endLine = event.data.indexOf('\n');
-    const command = event.data.slice(0, endLine === -1 ? undefined : endLine);
-    const payload = endLine === -1 ? "" : event.data.slice(endLine + 1);
-    switch (command) {
+    handleCommand(JSON.parse(event.data));
+}
+function handleCommand(command) {
+    switch (command.type) {
+        case "hello":
+            if (buildId !== null && command.build_id !== buildId) {
+                console.log("Missed changes while disconnected from penguin server: reloading...");
+                reload();
+            }
+            buildId = command.build_id;
+            sessionId = command.session;
+            sessionToken = command.token === undefined ? null : command.token;
+            flushPendingMessages();
+            break;
         case "reload":
             console.log("Received reload request from penguin server: reloading page...");
-            location.reload();
+            reload();
             break;
         case "message":
-            showMessage(payload);
+            showMessage(command.html);
             break;
+        case "error":
+            showError(command.diagnostic);
+            break;
+        case "clear":
+            clearOverlay();
+            break;
+        case "building":
+            showBuildIndicator();
+            break;
+        case "sync":
+            applySync(command.event);
+            break;
+        // Newer servers might send commands we don't know about yet.
         default:
-            throw new Error("unexpected WS command from penguin");
+            console.debug(`Ignoring unknown command '${command.type}' from penguin server`);
+    }
+}
+// Reloads the page, saving its state first if enabled.
+function reload() {
+    if (preserve_state) {
+        saveState();
+    }
+    location.reload();
+}
+function formFields() {
+    const out = [];
+    document.querySelectorAll("input, textarea, select").forEach(element => {
+        out.push(element);
+    });
+    return out;
+}
+// Returns whether the value of the field should be saved. Passwords are not
+// written to the storage and hidden fields are controlled by the page.
+function isPreservable(field) {
+    return !(field instanceof HTMLInputElement)
+        || ["password", "file", "hidden"].indexOf(field.type) === -1;
+}
+function saveState() {
+    const fields = formFields();
+    const active = document.activeElement;
+    const focusField = fields.indexOf(active);
+    const textField = focusField !== -1 && "selectionStart" in fields[focusField]
+        ? fields[focusField]
+        : null;
+    const state = {
+        url: location.href,
+        scrollX: window.scrollX,
+        scrollY: window.scrollY,
+        focusId: active === null || active.id === "" ? null : active.id,
+        focusField,
+        selectionStart: textField === null ? null : textField.selectionStart,
+        selectionEnd: textField === null ? null : textField.selectionEnd,
+        fields: fields.map(field => ({
+            name: field.name,
+            value: isPreservable(field) ? field.value : null,
+            checked: field instanceof HTMLInputElement && field.checked,
+        })),
+    };
+    try {
+        sessionStorage.setItem(STATE_KEY, JSON.stringify(state));
+    } catch (e) {
+        console.warn("penguin: failed to save page state", e);
+    }
+}
+function restoreState() {
+    let raw = null;
+    try {
+        raw = sessionStorage.getItem(STATE_KEY);
+        sessionStorage.removeItem(STATE_KEY);
+    } catch (e) {
+        return;
+    }
+    if (raw === null) {
+        return;
+    }
+    const state = JSON.parse(raw);
+    if (state.url !== location.href) {
+        return;
+    }
+    // Fields are matched by position and name, so values are only restored if
+    // the page structure did not change too much.
+    const fields = formFields();
+    state.fields.forEach((saved, i) => {
+        const field = fields[i];
+        if (field === undefined || field.name !== saved.name || saved.value === null) {
+            return;
+        }
+        const isToggle = field.type === "checkbox" || field.type === "radio";
+        if (field instanceof HTMLInputElement && isToggle) {
+            field.checked = saved.checked;
+        } else {
+            field.value = saved.value;
+        }
+    });
+    const focusElement = state.focusId === null
+        ? fields[state.focusField]
+        : document.getElementById(state.focusId);
+    if (focusElement !== undefined && focusElement !== null) {
+        focusElement.focus();
+        if (state.selectionStart !== null && "setSelectionRange" in focusElement) {
+            const input = focusElement;
+            input.setSelectionRange(state.selectionStart, state.selectionEnd);
+        }
+    }
+    // Loading images or fonts might change the layout, so we scroll again
+    // once everything is loaded.
+    window.scrollTo(state.scrollX, state.scrollY);
+    if (document.readyState !== "complete") {
+        window.addEventListener("load", () => window.scrollTo(state.scrollX, state.scrollY));
+    }
+}
+// Sends a message to the penguin server, queueing it if the connection is not
+// established yet.
+function sendMessage(message) {
+    sendData(JSON.stringify(message));
+}
+function sendData(data) {
+    if (transport === "ws") {
+        if (socket !== null && socket.readyState === WebSocket.OPEN) {
+            socket.send(data);
+        } else if (socket === null || socket.readyState === WebSocket.CONNECTING) {
+            pendingMessages.push(data);
+        }
+    } else if (sessionId === null) {
+        pendingMessages.push(data);
+    } else {
+        // Errors are ignored, as logging them would forward them again.
+        const url = `${control_path}/send?session=${sessionId}&token=${sessionToken}`;
+        fetch(url, { method: "POST", body: data }).catch(() => undefined);
+    }
+}
+function flushPendingMessages() {
+    const messages = pendingMessages;
+    pendingMessages = [];
+    messages.forEach(sendData);
+}
+// Tells the server when the page changes its URL without loading a new page,
+// e.g. in single page applications, so that the session can still be
+// addressed by its URL.
+function reportUrlChanges() {
+    let lastUrl = location.href;
+    const check = () => {
+        if (location.href !== lastUrl) {
+            lastUrl = location.href;
+            sendMessage({ type: "url", url: lastUrl });
+        }
+    };
+    const pushState = history.pushState;
+    history.pushState = function (data, unused, url) {
+        pushState.call(history, data, unused, url);
+        check();
+    };
+    const replaceState = history.replaceState;
+    history.replaceState = function (data, unused, url) {
+        replaceState.call(history, data, unused, url);
+        check();
+    };
+    window.addEventListener("popstate", check);
+    window.addEventListener("hashchange", check);
+}
+// Sends navigation, scroll and click events of this session to the server,
+// which relays them to all other sessions.
+function initSync() {
+    // Reloads and navigation caused by another session are not relayed.
+    let causedBySync = false;
+    try {
+        causedBySync = sessionStorage.getItem(SYNC_NAVIGATION_KEY) === location.href;
+        sessionStorage.removeItem(SYNC_NAVIGATION_KEY);
+    } catch (e) {
+        console.warn("penguin: failed to access session storage", e);
+    }
+    const entries = performance.getEntriesByType("navigation");
+    const entry = entries[0];
+    const isReload = entries.length > 0 && entry.type === "reload";
+    if (!causedBySync && !isReload) {
+        sendMessage({ type: "sync", kind: "navigate", url: location.href });
+    }
+    let scrollTimeout = null;
+    window.addEventListener("scroll", () => {
+        if (Date.now() < ignoreScrollUntil || scrollTimeout !== null) {
+            return;
+        }
+        scrollTimeout = window.setTimeout(() => {
+            scrollTimeout = null;
+            sendMessage({ type: "sync", kind: "scroll", position: scrollPosition() });
+        }, 50);
+    });
+    // Clicks triggered by sync mode are not trusted and thus not relayed.
+    document.addEventListener("click", event => {
+        if (event.isTrusted && event.target instanceof Element) {
+            sendMessage({ type: "sync", kind: "click", selector: cssPath(event.target) });
+        }
+    }, true);
+}
+// Applies an event relayed from another session.
+function applySync(event) {
+    if (event.kind === "navigate" && event.url !== undefined && event.url !== location.href) {
+        // Sync events are relayed from other sessions, so they must not
+        // navigate us to other sites (or `javascript:` URLs).
+        if (!isSameOrigin(event.url)) {
+            console.warn("penguin: ignoring sync navigation to other origin", event.url);
+            return;
+        }
+        try {
+            sessionStorage.setItem(SYNC_NAVIGATION_KEY, event.url);
+        } catch (e) {
+            console.warn("penguin: failed to access session storage", e);
+        }
+        location.href = event.url;
+    } else if (event.kind === "scroll" && event.position !== undefined) {
+        // The position is relative, as the sessions might have different
+        // screen sizes.
+        const max = document.documentElement.scrollHeight - window.innerHeight;
+        ignoreScrollUntil = Date.now() + 100;
+        window.scrollTo(window.scrollX, event.position * max);
+    } else if (event.kind === "click" && event.selector !== undefined) {
+        const element = document.querySelector(event.selector);
+        if (element instanceof HTMLElement) {
+            element.click();
+        }
+    }
+}
+function isSameOrigin(url) {
+    try {
+        return new URL(url, location.href).origin === location.origin;
+    } catch (e) {
+        return false;
+    }
+}
+// Returns the vertical scroll position between 0 (top) and 1 (bottom).
+function scrollPosition() {
+    const max = document.documentElement.scrollHeight - window.innerHeight;
+    return max <= 0 ? 0 : window.scrollY / max;
+}
+// Returns a CSS selector uniquely identifying the given element.
+function cssPath(element) {
+    const parts = [];
+    let current = element;
+    while (current !== null && current !== document.documentElement) {
+        if (current.id !== "") {
+            parts.unshift(`#${CSS.escape(current.id)}`);
+            break;
+        }
+        const parent = current.parentElement;
+        if (parent === null) {
+            break;
+        }
+        const index = Array.from(parent.children).indexOf(current) + 1;
+        parts.unshift(`${current.tagName.toLowerCase()}:nth-child(${index})`);
+        current = parent;
+    }
+    return parts.join(" > ");
+}
+// Wraps all `console` logging methods and listens for uncaught errors to send
+// them to the penguin server.
+function hookConsole() {
+    const LEVELS = [
+        "debug",
+        "log",
+        "info",
+        "warn",
+        "error",
+    ];
+    const anyConsole = console;
+    for (const level of LEVELS) {
+        const original = anyConsole[level];
+        anyConsole[level] = function () {
+            forwardLog(level, Array.from(arguments));
+            original.apply(console, arguments);
+        };
+    }
+    window.addEventListener("error", event => {
+        const error = event.error === undefined || event.error === null
+            ? event.message
+            : event.error;
+        forwardLog("error", ["Uncaught", error]);
+    });
+    window.addEventListener("unhandledrejection", event => {
+        forwardLog("error", ["Unhandled promise rejection:", event.reason]);
+    });
+}
+function forwardLog(level, args) {
+    const message = args.map(formatLogArg).join(" ");
+    sendMessage({ type: "console", level, message });
+}
+function formatLogArg(arg) {
+    if (typeof arg === "string") {
+        return arg;
+    }
+    if (arg instanceof Error) {
+        return arg.stack === undefined ? `${arg.name}: ${arg.message}` : arg.stack;
+    }
+    try {
+        const json = JSON.stringify(arg);
+        return json === undefined ? String(arg) : json;
+    } catch (e) {
+        return String(arg);
     }
 }
 function showMessage(message) {
-    let overlay = document.createElement("div");
+    let content = createElement("div");
+    content.innerHTML = message;
+    showOverlay(content);
+}
+function showError(diagnostic) {
+    const SEVERITY_COLORS = {
+        error: "#c0392b",
+        warning: "#d35400",
+        info: "#2471a3",
+    };
+    let content = createElement("div");
+    content.style.fontFamily = "sans-serif";
+    let title = createElement("h2");
+    title.innerText = diagnostic.title;
+    title.style.color = SEVERITY_COLORS[diagnostic.severity];
+    title.style.borderLeft = `6px solid ${SEVERITY_COLORS[diagnostic.severity]}`;
+    title.style.paddingLeft = "12px";
+    content.appendChild(title);
+    if (diagnostic.file !== null) {
+        let location = createElement("div");
+        location.innerText = [diagnostic.file, diagnostic.line, diagnostic.column]
+            .filter(part => part !== null)
+            .join(":");
+        location.style.fontFamily = "monospace";
+        location.style.fontSize = "16px";
+        location.style.marginBottom = "16px";
+        content.appendChild(location);
+    }
+    const codeBlock = (text) => {
+        let pre = createElement("pre");
+        pre.innerHTML = ansiToHtml(text);
+        pre.style.backgroundColor = "#1e1e1e";
+        pre.style.color = "#e5e5e5";
+        pre.style.padding = "12px";
+        pre.style.overflow = "auto";
+        pre.style.fontSize = "14px";
+        content.appendChild(pre);
+    };
+    codeBlock(diagnostic.message);
+    if (diagnostic.code_frame !== null) {
+        codeBlock(diagnostic.code_frame);
+    }
+    showOverlay(content);
+}
+// Converts text containing ANSI escape codes (e.g. colored compiler output)
+// into HTML. The text itself is HTML-escaped. Only color and font style codes
+// are interpreted, all other escape sequences are removed.
+function ansiToHtml(text) {
+    // The 16 basic colors: first the normal, then the bright variants.
+    const COLORS = [
+        "#000000",
+        "#cd3131",
+        "#0dbc79",
+        "#e5e510",
+        "#2472c8",
+        "#bc3fbc",
+        "#11a8cd",
+        "#e5e5e5",
+        "#666666",
+        "#f14c4c",
+        "#23d18b",
+        "#f5f543",
+        "#3b8eea",
+        "#d670d6",
+        "#29b8db",
+        "#ffffff",
+    ];
+    // Converts a color of the 256 color palette to CSS.
+    const color256 = (n) => {
+        if (n < 16) {
+            return COLORS[n];
+        } else if (n < 232) {
+            const level = (x) => x === 0 ? 0 : 55 + x * 40;
+            n -= 16;
+            const [r, g, b] = [Math.floor(n / 36), Math.floor(n / 6) % 6, n % 6].map(level);
+            return `rgb(${r}, ${g}, ${b})`;
+        } else {
+            const gray = 8 + (n - 232) * 10;
+            return `rgb(${gray}, ${gray}, ${gray})`;
+        }
+    };
+    const escape = (s) => s
+        .replace(/&/g, "&amp;")
+        .replace(/</g, "&lt;")
+        .replace(/>/g, "&gt;");
+    let bold = false;
+    let dim = false;
+    let italic = false;
+    let underline = false;
+    let fg = null;
+    let bg = null;
+    // Because of the capture groups, `parts` alternates between text, the
+    // parameters of an escape sequence and its final letter.
+    const parts = text.split(/\x1b\[([0-9;?]*)([A-Za-z])/);
+    let out = "";
+    for (let i = 0; i < parts.length; i += 3) {
+        const chunk = parts[i];
+        if (chunk.length > 0) {
+            const css = [];
+            if (bold) {
+                css.push("font-weight: bold");
+            }
+            if (dim) {
+                css.push("opacity: 0.7");
+            }
+            if (italic) {
+                css.push("font-style: italic");
+            }
+            if (underline) {
+                css.push("text-decoration: underline");
+            }
+            if (fg !== null) {
+                css.push(`color: ${fg}`);
+            }
+            if (bg !== null) {
+                css.push(`background-color: ${bg}`);
+            }
+            out += css.length === 0
+                ? escape(chunk)
+                : `<span style="${css.join("; ")}">${escape(chunk)}</span>`;
+        }
+        if (i + 2 >= parts.length || parts[i + 2] !== "m") {
+            continue;
+        }
+        // "Select graphic rendition" sequence
+        const codes = parts[i + 1].split(";").map(code => code === "" ? 0 : Number(code));
+        for (let j = 0; j < codes.length; j++) {
+            const code = codes[j];
+            if (code === 0) {
+                bold = dim = italic = underline = false;
+                fg = bg = null;
+            } else if (code === 1) {
+                bold = true;
+            } else if (code === 2) {
+                dim = true;
+            } else if (code === 3) {
+                italic = true;
+            } else if (code === 4) {
+                underline = true;
+            } else if (code === 22) {
+                bold = dim = false;
+            } else if (code === 23) {
+                italic = false;
+            } else if (code === 24) {
+                underline = false;
+            } else if (code >= 30 && code <= 37) {
+                fg = COLORS[code - 30];
+            } else if (code >= 90 && code <= 97) {
+                fg = COLORS[code - 90 + 8];
+            } else if (code === 39) {
+                fg = null;
+            } else if (code >= 40 && code <= 47) {
+                bg = COLORS[code - 40];
+            } else if (code >= 100 && code <= 107) {
+                bg = COLORS[code - 100 + 8];
+            } else if (code === 49) {
+                bg = null;
+            } else if (code === 38 || code === 48) {
+                let color = null;
+                if (codes[j + 1] === 5) {
+                    color = color256(codes[j + 2]);
+                    j += 2;
+                } else if (codes[j + 1] === 2) {
+                    color = `rgb(${codes[j + 2]}, ${codes[j + 3]}, ${codes[j + 4]})`;
+                    j += 4;
+                }
+                if (code === 38) {
+                    fg = color;
+                } else {
+                    bg = color;
+                }
+            }
+        }
+    }
+    return out;
+}
+// The currently shown message or error overlay. There is at most one.
+let currentOverlay = null;
+document.addEventListener("keydown", event => {
+    if (event.key === "Escape") {
+        clearOverlay();
+    }
+});
+function clearOverlay() {
+    hideBuildIndicator();
+    if (currentOverlay !== null) {
+        removeFromPage(currentOverlay);
+        currentOverlay = null;
+    }
+}
+// Small badge in the corner, shown while the server is building.
+let buildIndicator = null;
+function showBuildIndicator() {
+    if (buildIndicator !== null) {
+        return;
+    }
+    // We encode '…' as escape code to make this work with non-UTF8 HTML.
+    buildIndicator = createElement("div");
+    buildIndicator.innerText = "Building\u2026";
+    buildIndicator.style.position = "fixed";
+    buildIndicator.style.zIndex = "987654322";
+    buildIndicator.style.bottom = "16px";
+    buildIndicator.style.right = "16px";
+    buildIndicator.style.padding = "6px 12px";
+    buildIndicator.style.borderRadius = "4px";
+    buildIndicator.style.fontFamily = "sans-serif";
+    buildIndicator.style.fontSize = "14px";
+    buildIndicator.style.color = "white";
+    buildIndicator.style.backgroundColor = "#2471a3";
+    buildIndicator.style.boxShadow = "0 2px 6px rgba(0, 0, 0, 0.3)";
+    addToPage(buildIndicator, false);
+}
+function hideBuildIndicator() {
+    if (buildIndicator !== null) {
+        removeFromPage(buildIndicator);
+        buildIndicator = null;
+    }
+}
+// Shows an overlay with the given content, replacing the previous overlay.
+function showOverlay(content) {
+    clearOverlay();
+    let overlay = createElement("div");
     // We encode '✖' as escape code to make this work with non-UTF8 HTML.
-    let closeButton = document.createElement("button");
+    let closeButton = createElement("button");
     closeButton.innerText = "Close \u2716";
     closeButton.style.fontSize = "20px";
     closeButton.style.fontFamily = "sans-serif";
     closeButton.style.display = "inline-block";
     closeButton.style.cursor = "pointer";
-    closeButton.addEventListener("click", () => overlay.style.display = "none");
-    let header = document.createElement("div");
+    closeButton.addEventListener("click", clearOverlay);
+    let header = createElement("div");
     header.style.textAlign = "right";
     header.style.margin = "8px";
     header.appendChild(closeButton);
-    let content = document.createElement("div");
-    content.innerHTML = message;
     content.style.margin = "16px";
     content.style.height = "100%";
     overlay.appendChild(header);
@@ -106,5 +808,53 @@ function showMessage(message) {
     overlay.style.top = "0";
     overlay.style.left = "0";
     overlay.style.backgroundColor = "#ebebeb";
-    document.body.prepend(overlay);
+    overlay.style.overflow = "auto";
+    currentOverlay = overlay;
+    addToPage(overlay, true);
+}
+// Returns the element our UI elements are added to: the `body` in HTML
+// documents (`null` while it is not parsed yet) and the root element in SVG
+// documents, which don't have a `body`.
+function pageRoot() {
+    const root = document.documentElement;
+    return root instanceof SVGSVGElement ? root : document.body;
+}
+// Creates an HTML element. In SVG documents, `document.createElement` would
+// create an element without namespace, which has no `style` and is not
+// rendered.
+function createElement(tag) {
+    return document.createElementNS("http://www.w3.org/1999/xhtml", tag);
+}
+// Adds one of our UI elements to the page. In SVG documents, it has to be
+// wrapped in a `foreignObject` to be rendered.
+function addToPage(element, prepend) {
+    const root = pageRoot() || document.documentElement;
+    let child = element;
+    if (root instanceof SVGSVGElement) {
+        const wrapper = document.createElementNS("http://www.w3.org/2000/svg", "foreignObject");
+        wrapper.setAttribute("x", "0");
+        wrapper.setAttribute("y", "0");
+        wrapper.setAttribute("width", "100%");
+        wrapper.setAttribute("height", "100%");
+        // The wrapper covers the whole image, but only our element should
+        // receive clicks.
+        wrapper.style.pointerEvents = "none";
+        if (element.style.pointerEvents === "") {
+            element.style.pointerEvents = "auto";
+        }
+        wrapper.appendChild(element);
+        child = wrapper;
+    }
+    if (prepend) {
+        root.prepend(child);
+    } else {
+        root.appendChild(child);
+    }
+}
+function removeFromPage(element) {
+    const parent = element.parentNode;
+    element.remove();
+    if (parent instanceof SVGForeignObjectElement) {
+        parent.remove();
+    }
 }
@@ -1,39 +1,387 @@
-use crate::Config;
+use crate::{Config, InjectPosition};
 
 
 /// Returns the JS code within `<script>` tags.
 pub(crate) fn script(config: &Config) -> String {
     const JS_CODE: &str = include_str!("generated/browser.js");
 
-    JS_CODE.replace("{{ control_path }}", &config.control_path)
+    JS_CODE
+        .replace("{{ control_path }}", &config.control_path)
+        .replace("{{ forward_console }}", &config.forward_console.to_string())
+        .replace("{{ preserve_state }}", &config.preserve_state.to_string())
+        .replace("{{ sync }}", &config.sync.to_string())
+        .replace("{{ connection_badge }}", &config.connection_badge.to_string())
 }
 
-/// Injects our JS code into `input`. This function tries to find the closing
-/// `body` tag and insert the script right before it.
-pub(crate) fn into(input: &[u8], config: &Config) -> Vec<u8> {
-    // Try to find the closing `body` tag.
-    let mut body_close_idx = None;
-    let mut inside_comment = false;
-    for i in 0..input.len() {
-        let rest = &input[i..];
-        if !inside_comment && rest.starts_with(b"</body>") {
-            body_close_idx = Some(i);
-        } else if !inside_comment && rest.starts_with(b"<!--") {
-            inside_comment = true;
-        } else if inside_comment && rest.starts_with(b"-->") {
-            inside_comment = false;
+/// The kind of documents we inject our script into. This is shared by the
+/// file server and the proxy, so that both behave the same.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DocumentKind {
+    Html,
+    Xhtml,
+    Svg,
+}
+
+impl DocumentKind {
+    /// Returns the document kind for the given `Content-Type` header value or
+    /// `None` if we do not inject anything into that type.
+    pub(crate) fn from_content_type(content_type: &str) -> Option<Self> {
+        let essence = content_type.split(';').next().unwrap_or("").trim();
+        if essence.eq_ignore_ascii_case("text/html") {
+            Some(Self::Html)
+        } else if essence.eq_ignore_ascii_case("application/xhtml+xml") {
+            Some(Self::Xhtml)
+        } else if essence.eq_ignore_ascii_case("image/svg+xml") {
+            Some(Self::Svg)
+        } else {
+            None
         }
     }
+}
 
-    // If we haven't found a closing body tag, we just insert our JS at the very
-    // end.
-    let insert_idx = body_close_idx.unwrap_or(input.len());
-
-    let control_path = &config.control_path;
-    let script_tag = format!(r#"<script src="{control_path}/client.js" defer></script>"#);
+/// Injects our JS code into `input`. Where exactly is determined by
+/// `config.inject_position`. If `nonce` is given, it is added as attribute to
+/// our `<script>` tag to satisfy the page's CSP.
+pub(crate) fn into(
+    input: &[u8],
+    kind: DocumentKind,
+    config: &Config,
+    nonce: Option<&str>,
+) -> Vec<u8> {
+    let insert_idx = insert_position(input, kind, config.inject_position);
+    let script_tag = script_tag(kind, config, nonce);
 
     let mut out = input[..insert_idx].to_vec();
     out.extend_from_slice(script_tag.as_bytes());
     out.extend_from_slice(&input[insert_idx..]);
     out
 }
+
+/// Returns the `<script>` tag that loads our JS code.
+fn script_tag(kind: DocumentKind, config: &Config, nonce: Option<&str>) -> String {
+    use std::fmt::Write;
+
+    let mut attrs = String::new();
+    let mut add_attr = |name: &str, value: &str| {
+        let value = value.replace('&', "&amp;").replace('"', "&quot;");
+        write!(attrs, r#" {name}="{value}""#).unwrap();
+    };
+    if let Some(nonce) = nonce {
+        add_attr("nonce", nonce);
+    }
+    if let Some(integrity) = &config.script_integrity {
+        add_attr("integrity", integrity);
+    }
+    if let Some(crossorigin) = config.script_crossorigin {
+        add_attr("crossorigin", crossorigin.as_str());
+    }
+
+    let control_path = &config.control_path;
+    match kind {
+        DocumentKind::Html => {
+            format!(r#"<script src="{control_path}/client.js" defer{attrs}></script>"#)
+        }
+        // XML does not allow attributes without value.
+        DocumentKind::Xhtml => {
+            format!(r#"<script src="{control_path}/client.js" defer="defer"{attrs}></script>"#)
+        }
+        // SVG uses `href` instead of `src` and does not know `defer`. Since
+        // we insert at the very end by default, that's not a problem.
+        DocumentKind::Svg => format!(r#"<script href="{control_path}/client.js"{attrs}></script>"#),
+    }
+}
+
+/// Positions of interesting tags in a document.
+#[derive(Debug, Default)]
+struct Landmarks {
+    /// Right after the first opening `<head>` tag.
+    head_start: Option<usize>,
+    /// Start of the first closing `</head>` tag.
+    head_end: Option<usize>,
+    /// Start of the last closing `</body>` tag.
+    body_end: Option<usize>,
+    /// Right after the first opening tag of the root element (`<html>` or
+    /// `<svg>`).
+    root_start: Option<usize>,
+    /// Start of the last closing tag of the root element.
+    root_end: Option<usize>,
+}
+
+/// Returns the index in `input` at which our script should be inserted.
+fn insert_position(input: &[u8], kind: DocumentKind, position: InjectPosition) -> usize {
+    let landmarks = find_landmarks(input, kind);
+    let end = || landmarks.body_end.or(landmarks.root_end).unwrap_or(input.len());
+
+    match (kind, position) {
+        // SVG has neither `head` nor `body`.
+        (DocumentKind::Svg, InjectPosition::BodyEnd) => landmarks.root_end.unwrap_or(input.len()),
+        (DocumentKind::Svg, _) => landmarks.root_start.unwrap_or(input.len()),
+
+        (_, InjectPosition::HeadStart) => landmarks.head_start
+            .or(landmarks.root_start)
+            .unwrap_or_else(end),
+        (_, InjectPosition::HeadEnd) => landmarks.head_end
+            .or(landmarks.head_start)
+            .or(landmarks.root_start)
+            .unwrap_or_else(end),
+        (_, InjectPosition::BodyEnd) => end(),
+    }
+}
+
+/// Scans the document for the tags we are interested in. This is not a real
+/// HTML/XML parser, but it skips comments, `CDATA` sections and the contents of
+/// elements that may contain arbitrary text (like `<script>`), so that tags
+/// mentioned in there are not found. All tag names are compared case
+/// insensitively.
+fn find_landmarks(input: &[u8], kind: DocumentKind) -> Landmarks {
+    let root = match kind {
+        DocumentKind::Html | DocumentKind::Xhtml => "html",
+        DocumentKind::Svg => "svg",
+    };
+
+    let mut out = Landmarks::default();
+    let mut i = 0;
+    while i < input.len() {
+        let rest = &input[i..];
+        if input[i] != b'<' {
+            i += 1;
+        } else if rest.starts_with(b"<!--") {
+            match find(input, i + 4, b"-->") {
+                Some(end) => i = end + 3,
+                None => break,
+            }
+        } else if starts_with_ignore_case(rest, b"<![CDATA[") {
+            match find(input, i + 9, b"]]>") {
+                Some(end) => i = end + 3,
+                None => break,
+            }
+        } else if let Some(name) = ["script", "style", "textarea", "title"].iter()
+            .find(|name| is_tag(rest, name, false))
+        {
+            let Some(tag_end) = tag_end(input, i) else { break };
+
+            // In XML, `<script />` is allowed and has no contents.
+            i = if kind != DocumentKind::Html && input[tag_end - 2] == b'/' {
+                tag_end
+            } else {
+                let closing = format!("</{name}");
+                match find_ignore_case(input, tag_end, closing.as_bytes()) {
+                    Some(pos) => pos + closing.len(),
+                    None => break,
+                }
+            };
+        } else if is_tag(rest, "head", false) || is_tag(rest, root, false) {
+            let Some(tag_end) = tag_end(input, i) else { break };
+            let landmark = if is_tag(rest, "head", false) {
+                &mut out.head_start
+            } else {
+                &mut out.root_start
+            };
+            landmark.get_or_insert(tag_end);
+            i = tag_end;
+        } else {
+            if is_tag(rest, "head", true) {
+                out.head_end.get_or_insert(i);
+            } else if is_tag(rest, "body", true) {
+                out.body_end = Some(i);
+            } else if is_tag(rest, root, true) {
+                out.root_end = Some(i);
+            }
+            i += 1;
+        }
+    }
+
+    out
+}
+
+/// Checks whether `s` starts with an opening (or closing, if `closing` is
+/// true) tag with the given name.
+fn is_tag(s: &[u8], name: &str, closing: bool) -> bool {
+    let prefix_len = if closing { 2 } else { 1 };
+    let name_end = prefix_len + name.len();
+    let prefix: &[u8] = if closing { b"</" } else { b"<" };
+
+    s.starts_with(prefix)
+        && s.len() > name_end
+        && s[prefix_len..name_end].eq_ignore_ascii_case(name.as_bytes())
+        // Make sure we do not match `<header>` when looking for `<head>`.
+        && matches!(s[name_end], b'>' | b'/' | b' ' | b'\t' | b'\n' | b'\r' | b'\x0c')
+}
+
+/// Returns the index right after the `>` that closes the tag starting at
+/// `start`. Quoted attribute values are skipped.
+fn tag_end(input: &[u8], start: usize) -> Option<usize> {
+    let mut quote = None;
+    for (i, &b) in input.iter().enumerate().skip(start) {
+        match (quote, b) {
+            (None, b'"' | b'\'') => quote = Some(b),
+            (None, b'>') => return Some(i + 1),
+            (Some(q), _) if q == b => quote = None,
+            _ => {}
+        }
+    }
+
+    None
+}
+
+fn find(input: &[u8], start: usize, needle: &[u8]) -> Option<usize> {
+    input.get(start..)?
+        .windows(needle.len())
+        .position(|w| w == needle)
+        .map(|pos| pos + start)
+}
+
+fn find_ignore_case(input: &[u8], start: usize, needle: &[u8]) -> Option<usize> {
+    input.get(start..)?
+        .windows(needle.len())
+        .position(|w| w.eq_ignore_ascii_case(needle))
+        .map(|pos| pos + start)
+}
+
+fn starts_with_ignore_case(s: &[u8], prefix: &[u8]) -> bool {
+    s.len() >= prefix.len() && s[..prefix.len()].eq_ignore_ascii_case(prefix)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{DocumentKind, insert_position};
+    use crate::InjectPosition::{self, *};
+
+    /// Inserts `@` at the position where our script would be injected.
+    fn marked(input: &str, kind: DocumentKind, position: InjectPosition) -> String {
+        let idx = insert_position(input.as_bytes(), kind, position);
+        format!("{}@{}", &input[..idx], &input[idx..])
+    }
+
+    fn html(input: &str, position: InjectPosition) -> String {
+        marked(input, DocumentKind::Html, position)
+    }
+
+    #[test]
+    fn script_tags() {
+        use super::script_tag;
+        use crate::{CrossOrigin, Server};
+
+        let builder = Server::bind(([127, 0, 0, 1], 4090).into()).proxy("localhost:8000".parse().unwrap());
+        let config = builder.clone().validate().unwrap();
+        assert_eq!(
+            script_tag(DocumentKind::Html, &config, None),
+            r#"<script src="/~~penguin/client.js" defer></script>"#,
+        );
+        assert_eq!(
+            script_tag(DocumentKind::Xhtml, &config, Some("abc")),
+            r#"<script src="/~~penguin/client.js" defer="defer" nonce="abc"></script>"#,
+        );
+        assert_eq!(
+            script_tag(DocumentKind::Svg, &config, None),
+            r#"<script href="/~~penguin/client.js"></script>"#,
+        );
+
+        let config = builder
+            .script_integrity("sha384-\"x\"")
+            .script_crossorigin(CrossOrigin::Anonymous)
+            .validate()
+            .unwrap();
+        assert_eq!(
+            script_tag(DocumentKind::Html, &config, Some("n")),
+            "<script src=\"/~~penguin/client.js\" defer nonce=\"n\" \
+                integrity=\"sha384-&quot;x&quot;\" crossorigin=\"anonymous\"></script>",
+        );
+    }
+
+    #[test]
+    fn content_types() {
+        use super::DocumentKind as Kind;
+
+        assert_eq!(Kind::from_content_type("text/html"), Some(Kind::Html));
+        assert_eq!(Kind::from_content_type("text/html; charset=utf-8"), Some(Kind::Html));
+        assert_eq!(Kind::from_content_type("Text/HTML"), Some(Kind::Html));
+        assert_eq!(Kind::from_content_type("application/xhtml+xml"), Some(Kind::Xhtml));
+        assert_eq!(Kind::from_content_type("image/svg+xml"), Some(Kind::Svg));
+        assert_eq!(Kind::from_content_type("text/plain"), None);
+        assert_eq!(Kind::from_content_type("text/html-sandboxed"), None);
+    }
+
+    #[test]
+    fn body_end() {
+        assert_eq!(html("<body>x</body>", BodyEnd), "<body>x@</body>");
+        assert_eq!(html("<BODY>x</BODY>", BodyEnd), "<BODY>x@</BODY>");
+        assert_eq!(html("<body>x</body >", BodyEnd), "<body>x@</body >");
+        assert_eq!(html("<html><body>x</html>", BodyEnd), "<html><body>x@</html>");
+        assert_eq!(html("<p>x</p>", BodyEnd), "<p>x</p>@");
+        assert_eq!(html("<body></bodyx>", BodyEnd), "<body></bodyx>@");
+    }
+
+    #[test]
+    fn skip_non_markup() {
+        assert_eq!(
+            html("<body><!-- </body> --></body>", BodyEnd),
+            "<body><!-- </body> -->@</body>",
+        );
+        assert_eq!(
+            html("<body></body><!-- </body> -->", BodyEnd),
+            "<body>@</body><!-- </body> -->",
+        );
+        assert_eq!(
+            html("<body><script>let s = '</body>';</script></body>", BodyEnd),
+            "<body><script>let s = '</body>';</script>@</body>",
+        );
+        assert_eq!(
+            html("<body><SCRIPT type=\"a>b\">'</body>'</Script></body>", BodyEnd),
+            "<body><SCRIPT type=\"a>b\">'</body>'</Script>@</body>",
+        );
+        assert_eq!(
+            html("<body><textarea></body></textarea></body>", BodyEnd),
+            "<body><textarea></body></textarea>@</body>",
+        );
+        assert_eq!(
+            marked("<body><![CDATA[</body>]]></body>", DocumentKind::Xhtml, BodyEnd),
+            "<body><![CDATA[</body>]]>@</body>",
+        );
+        assert_eq!(
+            marked("<body><script src=\"a.js\"/></body>", DocumentKind::Xhtml, BodyEnd),
+            "<body><script src=\"a.js\"/>@</body>",
+        );
+    }
+
+    #[test]
+    fn head() {
+        let doc = "<!DOCTYPE html><html><head><title>a</title></head><body></body></html>";
+        assert_eq!(
+            html(doc, HeadStart),
+            "<!DOCTYPE html><html><head>@<title>a</title></head><body></body></html>",
+        );
+        assert_eq!(
+            html(doc, HeadEnd),
+            "<!DOCTYPE html><html><head><title>a</title>@</head><body></body></html>",
+        );
+        assert_eq!(
+            html("<HEAD lang=\"x\"><header></header></HEAD>", HeadStart),
+            "<HEAD lang=\"x\">@<header></header></HEAD>",
+        );
+        assert_eq!(
+            html("<head><!-- </head> --><title></head></title></head>", HeadEnd),
+            "<head><!-- </head> --><title></head></title>@</head>",
+        );
+
+        // Fallbacks
+        assert_eq!(html("<html><body></body></html>", HeadStart), "<html>@<body></body></html>");
+        assert_eq!(html("<html><body></body></html>", HeadEnd), "<html>@<body></body></html>");
+        assert_eq!(html("<body></body>", HeadStart), "<body>@</body>");
+        assert_eq!(html("<head><body></body>", HeadEnd), "<head>@<body></body>");
+    }
+
+    #[test]
+    fn svg() {
+        let doc = "<?xml version=\"1.0\"?><svg xmlns=\"http://www.w3.org/2000/svg\"><g/></SVG>";
+        assert_eq!(
+            marked(doc, DocumentKind::Svg, BodyEnd),
+            "<?xml version=\"1.0\"?><svg xmlns=\"http://www.w3.org/2000/svg\"><g/>@</SVG>",
+        );
+        assert_eq!(
+            marked(doc, DocumentKind::Svg, HeadStart),
+            "<?xml version=\"1.0\"?><svg xmlns=\"http://www.w3.org/2000/svg\">@<g/></SVG>",
+        );
+    }
+}
@@ -58,16 +58,106 @@
 //!   (no proxy configured), all remaining requests are answered with 404.
 //!
 //!
+//! # Control API
+//!
+//! Apart from using a [`Controller`], the server can be controlled via HTTP
+//! requests to `{control_path}/api/v1/…`. All endpoints reply with JSON.
+//! Failed requests are answered with an appropriate status code and a body
+//! like `{ "error": "…" }`.
+//!
+//! - `GET status`: information about the server, e.g. `{ "version": "0.1.9",
+//!   "uptime_secs": 12, "sessions": 2, "build_id": "…", "config": { … } }`.
+//!   `sessions` is the number of connected browser sessions, `build_id` is
+//!   explained in the WebSocket protocol section below.
+//! - `GET sessions`: list of connected browser sessions, e.g. `[{ "id": "3",
+//!   "user_agent": "…", "url": "http://localhost:4090/", "connected_since":
+//!   1700000000 }]` (see [`SessionInfo`]).
+//! - `POST reload`, `POST clear`, `POST building`: like
+//!   [`Controller::reload`], [`Controller::clear_message`] and
+//!   [`Controller::show_build_indicator`].
+//! - `POST message` with body `{ "html": "…" }`: like
+//!   [`Controller::show_message`].
+//! - `POST error` with a [`Diagnostic`] as body: like
+//!   [`Controller::show_error`].
+//!
+//! All `POST` endpoints reply with the number of sessions that received the
+//! command, e.g. `{ "sessions": 2 }`. They and `GET sessions` can be limited
+//! to some sessions with the query parameter `session=<id>` or
+//! `url=<pattern>` (see [`SessionFilter`]).
+//!
+//!
+//! # WebSocket protocol
+//!
+//! The injected script connects to the server via WebSocket at
+//! `{control_path}?protocol=1&url=<url>`, where `url` is the percent-encoded
+//! URL of the page. Other clients can implement the same protocol. All
+//! messages are JSON objects with a `type` field. Unknown message types must
+//! be ignored by both sides, so that new ones can be added without breaking
+//! older clients.
+//!
+//! Right after connecting, the server sends `{ "type": "hello", "protocol": 1,
+//! "version": "0.1.9", "build_id": "…", "session": "3" }`. `protocol` is the
+//! version actually spoken, which might be lower than the requested one.
+//! `build_id` changes whenever the server restarts or sends a reload, so that
+//! a client reconnecting after losing the connection knows whether it missed
+//! changes. `session` is the ID of the client's session. The client should
+//! then send `{ "type": "hello", "protocol": 1, "capabilities": ["reload", …]
+//! }`, listing the commands it understands. Other commands are not sent to it.
+//!
+//! Commands sent by the server:
+//!
+//! - `{ "type": "reload" }`: reload the page.
+//! - `{ "type": "message", "html": "…" }`: show a message in an overlay.
+//! - `{ "type": "error", "diagnostic": { … } }`: show a [`Diagnostic`].
+//! - `{ "type": "clear" }`: remove the message overlay.
+//! - `{ "type": "building" }`: show a build indicator.
+//! - `{ "type": "sync", "event": { … } }`: an event relayed from another
+//!   session in sync mode (see [`Builder::sync`]).
+//!
+//! Messages sent by the client:
+//!
+//! - `{ "type": "console", "level": "log", "message": "…" }`: console output
+//!   (see [`Builder::forward_console`]).
+//! - `{ "type": "sync", "kind": "…", … }`: an event to relay to all other
+//!   sessions in sync mode.
+//! - `{ "type": "url", "url": "…" }`: the page changed its URL without
+//!   loading a new page (e.g. via `history.pushState`). Updates
+//!   [`SessionInfo::url`].
+//!
+//! Clients not passing `protocol` use the legacy text protocol in which the
+//! server sends the command name, optionally followed by a newline and the
+//! payload (e.g. `message\n<html>`). They only get `reload` and `message`;
+//! diagnostics are sent to them as a `message`.
+//!
+//! For browsers that can't use WebSockets, there are two HTTP based
+//! alternatives speaking the same JSON protocol (without client hello), both
+//! accepting the `url` query parameter:
+//!
+//! - `GET {control_path}/events`: server-sent events, each containing one
+//!   message.
+//! - `GET {control_path}/poll`: long-polling. Replies with a JSON array of
+//!   messages, waiting up to 25 seconds for one to arrive. The first request
+//!   registers a session and replies with the hello message. Subsequent
+//!   requests need to pass `session=<id>&token=<token>`. Sessions not polling
+//!   for a minute are removed and further requests for them are answered with
+//!   404.
+//!
+//! With both, the hello message additionally contains a `token`, which has to
+//! be passed along with the session ID. Clients send messages via `POST
+//! {control_path}/send?session=<id>&token=<token>`.
+//!
+//!
 
 #![deny(missing_debug_implementations)]
 
-use std::{fmt, future::Future, net::SocketAddr, pin::Pin, task};
+use std::{fmt, future::Future, net::SocketAddr, pin::Pin, sync::{Arc, RwLock}, task};
 
 use tokio::sync::broadcast::{self, Sender};
 
 mod config;
 mod inject;
 mod serve;
+mod session;
 pub mod util;
 mod ws;
 
@@ -78,8 +168,12 @@ mod tests;
 pub extern crate hyper;
 
 pub use config::{
-    Builder, Config, ConfigError, DEFAULT_CONTROL_PATH, Mount, ProxyTarget, ProxyTargetParseError
+    Builder, Config, ConfigError, Cors, CrossOrigin, CustomHeader, DEFAULT_CONTROL_PATH,
+    InjectPosition, Mount, ProxyTarget, ProxyTargetParseError,
 };
+pub use session::{ConsoleLevel, Event, EventStream, SessionFilter, SessionId, SessionInfo};
+
+use session::Sessions;
 
 /// Penguin server: the main type of this library.
 ///
@@ -102,8 +196,15 @@ impl Server {
     /// you can use [`Builder::build`] instead of this method.
     pub fn build(config: Config) -> (Self, Controller) {
         let (sender, _) = broadcast::channel(ACTION_CHANNEL_SIZE);
-        let controller = Controller(sender.clone());
-        let future = Box::pin(serve::run(config, sender));
+        let sessions = Arc::new(Sessions::default());
+        let config = Arc::new(RwLock::new(Arc::new(config)));
+        let controller = Controller {
+            actions: sender.clone(),
+            sessions: Arc::clone(&sessions),
+            config: Arc::clone(&config),
+            filter: SessionFilter::All,
+        };
+        let future = Box::pin(serve::run(config, sender, sessions));
 
         (Self { future }, controller)
     }
@@ -126,13 +227,61 @@ impl fmt::Debug for Server {
 const ACTION_CHANNEL_SIZE: usize = 64;
 
 /// A handle to send commands to the server.
+///
+/// By default, commands are sent to all active browser sessions. Use
+/// [`Controller::select`] to only address some of them.
 #[derive(Debug, Clone)]
-pub struct Controller(Sender<Action>);
+pub struct Controller {
+    actions: Sender<(SessionFilter, Action)>,
+    sessions: Arc<Sessions>,
+    config: Arc<RwLock<Arc<Config>>>,
+    filter: SessionFilter,
+}
 
 impl Controller {
+    /// Returns a controller that sends commands only to the browser sessions
+    /// matching `filter`, replacing the filter of `self`.
+    ///
+    /// ```no_run
+    /// # fn foo(controller: penguin::Controller) {
+    /// use penguin::SessionFilter;
+    ///
+    /// controller.select(SessionFilter::Url("*/docs/*".into())).reload();
+    /// # }
+    /// ```
+    pub fn select(&self, filter: SessionFilter) -> Self {
+        Self { filter, ..self.clone() }
+    }
+
+    /// Returns all active browser sessions addressed by this controller,
+    /// ordered by ID.
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        self.sessions.list(&self.filter)
+    }
+
+    /// Returns a stream of events about browser sessions, e.g. sessions
+    /// connecting or disconnecting. Events of all sessions are yielded,
+    /// regardless of [`Controller::select`].
+    ///
+    /// ```no_run
+    /// # async fn foo(controller: penguin::Controller) {
+    /// use penguin::Event;
+    ///
+    /// let mut events = controller.subscribe_events();
+    /// while let Some(event) = events.recv().await {
+    ///     if let Event::ClientConnected(session) = event {
+    ///         println!("Browser connected: {:?}", session.user_agent);
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub fn subscribe_events(&self) -> EventStream {
+        self.sessions.subscribe()
+    }
+
     /// Reloads all active browser sessions.
     pub fn reload(&self) {
-        let _ = self.0.send(Action::Reload);
+        self.send(Action::Reload);
     }
 
     /// Shows a message as overlay in all active browser sessions. The given
@@ -140,12 +289,131 @@ impl Controller {
     ///
     /// This call will overwrite/hide all previous messages.
     pub fn show_message(&self, msg: impl Into<String>) {
-        let _ = self.0.send(Action::Message(msg.into()));
+        self.send(Action::Message(msg.into()));
+    }
+
+    /// Shows a diagnostic (e.g. a build error) as overlay in all active
+    /// browser sessions. In contrast to [`Controller::show_message`], you
+    /// don't need to build and escape HTML yourself: the overlay is rendered
+    /// by penguin and ANSI color codes (e.g. in compiler output) are
+    /// converted to colors.
+    ///
+    /// The same can be done by sending the diagnostic as JSON via `POST` to
+    /// `{control_path}/error`.
+    pub fn show_error(&self, diagnostic: Diagnostic) {
+        self.send(Action::Error(diagnostic));
+    }
+
+    /// Hides the message or error overlay (see [`Controller::show_message`]
+    /// and [`Controller::show_error`]) in all active browser sessions, without
+    /// reloading them. Does nothing in sessions that don't show any overlay.
+    ///
+    /// The same can be done by sending a `POST` request to
+    /// `{control_path}/clear`.
+    pub fn clear_message(&self) {
+        self.send(Action::ClearMessage);
+    }
+
+    /// Shows a small "building…" indicator in all active browser sessions,
+    /// e.g. while a build triggered by a file change is running. The
+    /// indicator disappears when the page is reloaded, a message or error is
+    /// shown, or [`Controller::clear_message`] is called.
+    pub fn show_build_indicator(&self) {
+        self.send(Action::Building);
+    }
+
+    /// Replaces the configuration of the running server, e.g. to change
+    /// mounts or the proxy target without restarting it. Requests that are
+    /// already being handled still use the old configuration. Browser
+    /// sessions stay connected but are not reloaded: call
+    /// [`Controller::reload`] for that.
+    ///
+    /// The server keeps listening on its original address, so
+    /// `config.bind_addr` is ignored. Unlike the other methods, this is not
+    /// affected by [`Controller::select`].
+    pub fn update_config(&self, mut config: Config) {
+        let mut current = self.config.write().unwrap();
+        if config.bind_addr != current.bind_addr {
+            log::warn!(
+                "Cannot change bind address of running server to {}, keeping {}",
+                config.bind_addr,
+                current.bind_addr,
+            );
+            config.bind_addr = current.bind_addr;
+        }
+
+        log::debug!("Updating server configuration");
+        *current = Arc::new(config);
     }
+
+    fn send(&self, action: Action) {
+        if let Action::Reload = action {
+            self.sessions.note_reload();
+        }
+        let _ = self.actions.send((self.filter.clone(), action));
+    }
+}
+
+/// A structured diagnostic, e.g. a compiler error, that can be shown in the
+/// browser via [`Controller::show_error`].
+///
+/// All fields except `title` and `message` are optional in the JSON
+/// representation. To create a value in Rust, you can use
+/// `Diagnostic { title, message, ..Default::default() }`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Diagnostic {
+    /// Short summary, shown as heading.
+    pub title: String,
+
+    /// Path of the file the diagnostic refers to.
+    #[serde(default)]
+    pub file: Option<String>,
+
+    /// Line in `file` (1-based).
+    #[serde(default)]
+    pub line: Option<u32>,
+
+    /// Column in `line` (1-based).
+    #[serde(default)]
+    pub column: Option<u32>,
+
+    /// The main message. May contain ANSI color codes.
+    pub message: String,
+
+    /// Excerpt of the source code around the location of the diagnostic.
+    /// May contain ANSI color codes.
+    #[serde(default)]
+    pub code_frame: Option<String>,
+
+    /// How severe the diagnostic is, which determines the color of the
+    /// overlay. Defaults to [`Severity::Error`].
+    #[serde(default)]
+    pub severity: Severity,
+}
+
+/// Severity of a [`Diagnostic`]. Serialized as `"error"`, `"warning"` or
+/// `"info"`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    #[default]
+    Error,
+    Warning,
+    Info,
 }
 
 #[derive(Debug, Clone)]
 enum Action {
     Reload,
     Message(String),
+    Error(Diagnostic),
+    ClearMessage,
+    Building,
+
+    /// A sync mode event from the given session, to be relayed to all other
+    /// sessions.
+    Sync {
+        from: SessionId,
+        event: serde_json::Map<String, serde_json::Value>,
+    },
 }
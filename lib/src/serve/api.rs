@@ -0,0 +1,346 @@
+//! The versioned JSON control API, available under `{control_path}/api/v1/`.
+
+use std::time::UNIX_EPOCH;
+
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde_json::{Value, json};
+use tokio::sync::broadcast::Sender;
+
+use crate::{Action, Config, Diagnostic, InjectPosition, SessionFilter, SessionInfo};
+use super::{Context, SERVER_HEADER};
+
+
+/// Handles a request to the API. `path` is the part after `/api/v1`.
+pub(crate) async fn handle(
+    req: Request<Body>,
+    path: &str,
+    config: &Config,
+    ctx: &Context,
+    actions: Sender<(SessionFilter, Action)>,
+) -> Response<Body> {
+    log::trace!("Handling request to JSON control API: {} {}", req.method(), path);
+
+    let filter = match session_filter(req.uri().query().unwrap_or("")) {
+        Ok(filter) => filter,
+        Err(msg) => return error(StatusCode::BAD_REQUEST, msg),
+    };
+    let send_action = |action| send(ctx, &actions, filter.clone(), action);
+
+    match (req.method(), path) {
+        (&Method::GET, "/status") => ok(json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "uptime_secs": ctx.started.elapsed().as_secs(),
+            "sessions": ctx.sessions.count(&SessionFilter::All),
+            "build_id": ctx.sessions.build_id(),
+            "config": config_json(config),
+        })),
+
+        (&Method::GET, "/sessions") => {
+            let sessions = ctx.sessions.list(&filter).iter().map(session_json).collect();
+            ok(Value::Array(sessions))
+        }
+
+        (&Method::POST, "/reload") => send_action(Action::Reload),
+        (&Method::POST, "/clear") => send_action(Action::ClearMessage),
+        (&Method::POST, "/building") => send_action(Action::Building),
+
+        (&Method::POST, "/message") => {
+            #[derive(serde::Deserialize)]
+            struct Message {
+                html: String,
+            }
+
+            match read_json::<Message>(req).await {
+                Ok(body) => send_action(Action::Message(body.html)),
+                Err(response) => response,
+            }
+        }
+
+        (&Method::POST, "/error") => match read_json::<Diagnostic>(req).await {
+            Ok(diagnostic) => send_action(Action::Error(diagnostic)),
+            Err(response) => response,
+        },
+
+        (_, "/status" | "/sessions" | "/reload" | "/clear" | "/building" | "/message" | "/error") => {
+            error(StatusCode::METHOD_NOT_ALLOWED, format!("method {} not allowed", req.method()))
+        }
+        _ => error(StatusCode::NOT_FOUND, format!("unknown API endpoint '{}'", path)),
+    }
+}
+
+/// Sends an action to all sessions selected by `filter` and replies with the
+/// number of sessions that received it.
+fn send(
+    ctx: &Context,
+    actions: &Sender<(SessionFilter, Action)>,
+    filter: SessionFilter,
+    action: Action,
+) -> Response<Body> {
+    log::debug!("Received {:?} for {:?} via JSON control API", action, filter);
+
+    let sessions = ctx.sessions.count(&filter);
+    if let Action::Reload = action {
+        ctx.sessions.note_reload();
+    }
+
+    // An error only means that there are currently no sessions.
+    let _ = actions.send((filter, action));
+    ok(json!({ "sessions": sessions }))
+}
+
+/// Parses the optional `session=<id>` or `url=<pattern>` query parameters.
+fn session_filter(query: &str) -> Result<SessionFilter, String> {
+    let mut filter = SessionFilter::All;
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = percent_encoding::percent_decode_str(value)
+            .decode_utf8()
+            .map_err(|_| "query is not valid UTF-8".to_owned())?;
+
+        let new = match key {
+            "session" => value.parse()
+                .map(SessionFilter::Id)
+                .map_err(|_| format!("invalid session ID '{}'", value))?,
+            "url" => SessionFilter::Url(value.into_owned()),
+            _ => continue,
+        };
+
+        if filter != SessionFilter::All {
+            return Err("only one of 'session' and 'url' can be specified".into());
+        }
+        filter = new;
+    }
+
+    Ok(filter)
+}
+
+fn session_json(session: &SessionInfo) -> Value {
+    json!({
+        "id": session.id.to_string(),
+        "user_agent": session.user_agent,
+        "url": session.url,
+        "connected_since": session.connected_since
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+    })
+}
+
+async fn read_json<T>(req: Request<Body>) -> Result<T, Response<Body>>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let body = hyper::body::to_bytes(req.into_body())
+        .await
+        .map_err(|e| error(StatusCode::BAD_REQUEST, format!("failed to read body: {}", e)))?;
+
+    serde_json::from_slice(&body)
+        .map_err(|e| error(StatusCode::BAD_REQUEST, format!("invalid JSON body: {}", e)))
+}
+
+fn config_json(config: &Config) -> Value {
+    let mounts = config.mounts.iter().map(|m| json!({
+        "uri_path": m.uri_path,
+        "fs_path": m.fs_path,
+        "follow_symlinks": m.follow_symlinks,
+    })).collect::<Vec<_>>();
+    let headers = config.headers.iter().map(|h| json!({
+        "route": h.route,
+        "name": h.name,
+        "value": h.value,
+    })).collect::<Vec<_>>();
+
+    json!({
+        "bind_addr": config.bind_addr.to_string(),
+        "proxy": config.proxy.as_ref().map(|p| p.to_string()),
+        "mounts": mounts,
+        "control_path": config.control_path,
+        "inject_position": match config.inject_position {
+            InjectPosition::HeadStart => "head-start",
+            InjectPosition::HeadEnd => "head-end",
+            InjectPosition::BodyEnd => "body-end",
+        },
+        "mime_types": config.mime_types,
+        "forward_console": config.forward_console,
+        "preserve_state": config.preserve_state,
+        "sync": config.sync,
+        "connection_badge": config.connection_badge,
+        "headers": headers,
+        "proxy_headers": config.proxy_headers,
+        "cors": config.cors.as_ref().map(|cors| json!({
+            "origins": cors.origins,
+            "methods": cors.methods,
+            "headers": cors.headers,
+            "credentials": cors.credentials,
+        })),
+    })
+}
+
+fn ok(body: Value) -> Response<Body> {
+    json_response(StatusCode::OK, body)
+}
+
+fn error(status: StatusCode, msg: String) -> Response<Body> {
+    log::debug!("Replying {} via JSON control API: {}", status, msg);
+    json_response(status, json!({ "error": msg }))
+}
+
+fn json_response(status: StatusCode, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .header("Server", SERVER_HEADER)
+        .body(body.to_string().into())
+        .expect("bug: invalid response")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::{Arc, RwLock}, time::Instant};
+
+    use hyper::{Body, Method, Request, Response, StatusCode};
+    use serde_json::{Value, json};
+    use tokio::sync::broadcast::{self, Sender};
+
+    use crate::{Action, Server, SessionFilter};
+    use super::super::{Context, proxy::ProxyContext};
+
+    fn context() -> (Context, Sender<(SessionFilter, Action)>) {
+        let config = Server::bind(([127, 0, 0, 1], 4090).into())
+            .proxy("localhost:8000".parse().unwrap())
+            .add_mount("/assets", Path::new("static")).unwrap()
+            .validate()
+            .unwrap();
+        let ctx = Context {
+            config: Arc::new(RwLock::new(Arc::new(config))),
+            proxy: ProxyContext::new(),
+            started: Instant::now(),
+            sessions: Default::default(),
+            pollers: Default::default(),
+            tokens: Default::default(),
+        };
+        (ctx, broadcast::channel(16).0)
+    }
+
+    /// Sends a request to the API and returns the status and JSON body of the
+    /// response.
+    async fn request(
+        ctx: &Context,
+        actions: &Sender<(SessionFilter, Action)>,
+        method: Method,
+        uri: &str,
+        body: &str,
+    ) -> (StatusCode, Value) {
+        let req = Request::builder()
+            .method(method)
+            .uri(format!("/~~penguin/api/v1{}", uri))
+            .body(body.to_owned().into())
+            .unwrap();
+        let path = req.uri().path().strip_prefix("/~~penguin/api/v1").unwrap().to_owned();
+        let response = super::handle(req, &path, &ctx.config(), ctx, actions.clone()).await;
+        json(response).await
+    }
+
+    async fn json(response: Response<Body>) -> (StatusCode, Value) {
+        let status = response.status();
+        assert_eq!(response.headers()["Content-Type"], "application/json");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[track_caller]
+    fn assert_error(actual: (StatusCode, Value), status: StatusCode) {
+        assert_eq!(actual.0, status);
+        assert!(actual.1["error"].is_string(), "no error message in {}", actual.1);
+    }
+
+    #[tokio::test]
+    async fn status() {
+        let (ctx, actions) = context();
+        let _session = ctx.sessions.add(None, Some("http://localhost:4090/".into()));
+
+        let (status, body) = request(&ctx, &actions, Method::GET, "/status", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+        assert!(body["uptime_secs"].is_u64());
+        assert_eq!(body["sessions"], 1);
+        assert_eq!(body["build_id"], ctx.sessions.build_id());
+
+        let config = &body["config"];
+        assert_eq!(config["bind_addr"], "127.0.0.1:4090");
+        assert_eq!(config["proxy"], "http://localhost:8000");
+        assert_eq!(config["mounts"], json!([{
+            "uri_path": "/assets",
+            "fs_path": "static",
+            "follow_symlinks": false,
+        }]));
+        assert_eq!(config["control_path"], "/~~penguin");
+        assert_eq!(config["inject_position"], "body-end");
+        assert_eq!(config["cors"], Value::Null);
+
+        // A reload changes the build ID.
+        request(&ctx, &actions, Method::POST, "/reload", "").await;
+        let (_, after) = request(&ctx, &actions, Method::GET, "/status", "").await;
+        assert_ne!(after["build_id"], body["build_id"]);
+    }
+
+    #[tokio::test]
+    async fn actions_count_sessions() {
+        let (ctx, actions) = context();
+        let mut receiver = actions.subscribe();
+        let docs = ctx.sessions.add(None, Some("http://localhost:4090/docs/a".into()));
+        let _index = ctx.sessions.add(None, Some("http://localhost:4090/".into()));
+
+        let by_id = format!("/reload?session={}", docs.id);
+        for (uri, count) in [
+            ("/reload", 2),
+            ("/reload?url=http%3A%2F%2Flocalhost%3A4090%2Fdocs%2F*", 1),
+            (&by_id, 1),
+            ("/reload?session=999", 0),
+        ] {
+            let response = request(&ctx, &actions, Method::POST, uri, "").await;
+            assert_eq!(response, (StatusCode::OK, json!({ "sessions": count })), "{}", uri);
+        }
+
+        let message = r#"{ "html": "<b>hi</b>" }"#;
+        let response = request(&ctx, &actions, Method::POST, "/message", message).await;
+        assert_eq!(response, (StatusCode::OK, json!({ "sessions": 2 })));
+
+        // The actions were sent with the requested filters.
+        let mut sent = Vec::new();
+        while let Ok(action) = receiver.try_recv() {
+            sent.push(action);
+        }
+        assert!(matches!(&sent[..], [
+            (SessionFilter::All, Action::Reload),
+            (SessionFilter::Url(_), Action::Reload),
+            (SessionFilter::Id(id), Action::Reload),
+            (SessionFilter::Id(_), Action::Reload),
+            (SessionFilter::All, Action::Message(html)),
+        ] if *id == docs.id && html == "<b>hi</b>"));
+    }
+
+    #[tokio::test]
+    async fn errors() {
+        let (ctx, actions) = context();
+        let get = |uri| request(&ctx, &actions, Method::GET, uri, "");
+        let post = |uri, body| request(&ctx, &actions, Method::POST, uri, body);
+
+        assert_error(get("/nope").await, StatusCode::NOT_FOUND);
+        assert_error(post("/status", "").await, StatusCode::METHOD_NOT_ALLOWED);
+        assert_error(get("/reload").await, StatusCode::METHOD_NOT_ALLOWED);
+        assert_error(get("/message").await, StatusCode::METHOD_NOT_ALLOWED);
+
+        // Bad JSON bodies.
+        assert_error(post("/message", "").await, StatusCode::BAD_REQUEST);
+        assert_error(post("/message", "<b>hi</b>").await, StatusCode::BAD_REQUEST);
+        assert_error(post("/message", r#"{ "text": "hi" }"#).await, StatusCode::BAD_REQUEST);
+        assert_error(post("/error", r#"{ "title": 3 }"#).await, StatusCode::BAD_REQUEST);
+
+        // Bad session filters.
+        assert_error(get("/sessions?session=1&url=*").await, StatusCode::BAD_REQUEST);
+        assert_error(post("/reload?url=*&session=1", "").await, StatusCode::BAD_REQUEST);
+        assert_error(post("/reload?session=abc", "").await, StatusCode::BAD_REQUEST);
+    }
+}
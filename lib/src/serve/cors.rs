@@ -0,0 +1,197 @@
+//! Handling of cross-origin requests (CORS), see `Builder::cors`.
+
+use hyper::{
+    Body, Method, Request, Response, StatusCode,
+    header::{self, HeaderMap, HeaderValue},
+};
+
+use crate::Cors;
+use super::SERVER_HEADER;
+
+
+/// How long browsers may cache the result of a preflight request.
+const MAX_AGE_SECS: &str = "600";
+
+/// Answers the request if it is a preflight request.
+pub(crate) fn preflight(req: &Request<Body>, cors: &Cors) -> Option<Response<Body>> {
+    if req.method() != Method::OPTIONS {
+        return None;
+    }
+    let origin = req.headers().get(header::ORIGIN)?;
+    let method = req.headers().get(header::ACCESS_CONTROL_REQUEST_METHOD)?;
+
+    log::trace!("Answering CORS preflight request from {:?}", origin);
+    let mut response = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header("Server", SERVER_HEADER)
+        .body(Body::empty())
+        .expect("bug: invalid response");
+
+    let headers = response.headers_mut();
+    headers.insert(
+        header::VARY,
+        HeaderValue::from_static(
+            "Origin, Access-Control-Request-Method, Access-Control-Request-Headers",
+        ),
+    );
+    if !is_allowed(origin, cors) {
+        // Without CORS headers, the browser rejects the actual request.
+        log::debug!("Origin {:?} is not allowed via CORS", origin);
+        return Some(response);
+    }
+
+    add_origin_headers(headers, origin, cors);
+    if let Some(methods) = allowed_list(&cors.methods, Some(method)) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, methods);
+    }
+    let requested_headers = req.headers().get(header::ACCESS_CONTROL_REQUEST_HEADERS);
+    if let Some(allowed_headers) = allowed_list(&cors.headers, requested_headers) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allowed_headers);
+    }
+    headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static(MAX_AGE_SECS));
+
+    Some(response)
+}
+
+/// Adds CORS headers to the response of a request from `origin`.
+pub(crate) fn add_headers(
+    mut response: Response<Body>,
+    origin: Option<&HeaderValue>,
+    cors: &Cors,
+) -> Response<Body> {
+    let headers = response.headers_mut();
+    headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    if let Some(origin) = origin.filter(|origin| is_allowed(origin, cors)) {
+        add_origin_headers(headers, origin, cors);
+    }
+
+    response
+}
+
+fn is_allowed(origin: &HeaderValue, cors: &Cors) -> bool {
+    cors.origins.iter().any(|allowed| {
+        allowed == "*" || allowed.as_bytes().eq_ignore_ascii_case(origin.as_bytes())
+    })
+}
+
+fn add_origin_headers(headers: &mut HeaderMap, origin: &HeaderValue, cors: &Cors) {
+    // `Builder::validate` makes sure that credentials are never allowed for
+    // all origins.
+    let value = if cors.origins.iter().any(|allowed| allowed == "*") {
+        HeaderValue::from_static("*")
+    } else {
+        origin.clone()
+    };
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, value);
+    if cors.credentials {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+    }
+}
+
+/// Returns the value for `Access-Control-Allow-Methods/Headers`: the
+/// requested value if `*` is allowed, the allowed values otherwise.
+fn allowed_list(allowed: &[String], requested: Option<&HeaderValue>) -> Option<HeaderValue> {
+    if allowed.iter().any(|v| v == "*") {
+        return requested.cloned();
+    }
+    if allowed.is_empty() {
+        return None;
+    }
+
+    // The values were checked in `Builder::validate`.
+    Some(HeaderValue::from_str(&allowed.join(", ")).unwrap())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Body, Method, Request, Response, StatusCode, header::HeaderValue};
+
+    use crate::Cors;
+    use super::{add_headers, preflight};
+
+    fn request(method: Method, headers: &[(&str, &str)]) -> Request<Body> {
+        let mut builder = Request::builder().method(method).uri("/api/items");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn preflights() {
+        let cors = Cors {
+            origins: vec!["http://localhost:3000".into()],
+            headers: vec!["Content-Type".into()],
+            ..Cors::default()
+        };
+        let preflight_from = |origin| preflight(&request(Method::OPTIONS, &[
+            ("Origin", origin),
+            ("Access-Control-Request-Method", "PUT"),
+            ("Access-Control-Request-Headers", "content-type, x-foo"),
+        ]), &cors);
+
+        let response = preflight_from("http://localhost:3000").unwrap();
+        let headers = response.headers();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(headers["Access-Control-Allow-Origin"], "http://localhost:3000");
+        assert_eq!(headers["Access-Control-Allow-Methods"], "GET, HEAD, POST");
+        assert_eq!(headers["Access-Control-Allow-Headers"], "Content-Type");
+        assert!(!headers.contains_key("Access-Control-Allow-Credentials"));
+
+        let response = preflight_from("http://evil.com").unwrap();
+        assert!(!response.headers().contains_key("Access-Control-Allow-Origin"));
+
+        // Not preflight requests.
+        assert!(preflight(&request(Method::OPTIONS, &[("Origin", "http://a.com")]), &cors)
+            .is_none());
+        assert!(preflight(&request(Method::GET, &[
+            ("Origin", "http://localhost:3000"),
+            ("Access-Control-Request-Method", "PUT"),
+        ]), &cors).is_none());
+
+        // Permissive mode allows all origins and mirrors the requested method
+        // and headers.
+        let response = preflight(&request(Method::OPTIONS, &[
+            ("Origin", "http://a.com"),
+            ("Access-Control-Request-Method", "PUT"),
+            ("Access-Control-Request-Headers", "x-foo"),
+        ]), &Cors::permissive()).unwrap();
+        let headers = response.headers();
+        assert_eq!(headers["Access-Control-Allow-Origin"], "*");
+        assert_eq!(headers["Access-Control-Allow-Methods"], "PUT");
+        assert_eq!(headers["Access-Control-Allow-Headers"], "x-foo");
+        assert!(!headers.contains_key("Access-Control-Allow-Credentials"));
+
+        // Credentials are only allowed for explicitly listed origins, which
+        // are mirrored.
+        let cors = Cors { credentials: true, ..cors };
+        let response = preflight(&request(Method::OPTIONS, &[
+            ("Origin", "http://localhost:3000"),
+            ("Access-Control-Request-Method", "PUT"),
+        ]), &cors).unwrap();
+        let headers = response.headers();
+        assert_eq!(headers["Access-Control-Allow-Origin"], "http://localhost:3000");
+        assert_eq!(headers["Access-Control-Allow-Credentials"], "true");
+    }
+
+    #[test]
+    fn response_headers() {
+        let cors = Cors { origins: vec!["http://localhost:3000".into()], ..Cors::default() };
+        let response = |origin: Option<&str>| {
+            let origin = origin.map(HeaderValue::from_str).transpose().unwrap();
+            let response = Response::builder()
+                .header("Vary", "Accept-Encoding")
+                .body(Body::empty())
+                .unwrap();
+            add_headers(response, origin.as_ref(), &cors)
+        };
+
+        let allowed = response(Some("http://localhost:3000"));
+        assert_eq!(allowed.headers()["Access-Control-Allow-Origin"], "http://localhost:3000");
+        assert_eq!(allowed.headers().get_all("Vary").iter().count(), 2);
+        assert!(!response(Some("http://evil.com")).headers()
+            .contains_key("Access-Control-Allow-Origin"));
+        assert!(!response(None).headers().contains_key("Access-Control-Allow-Origin"));
+    }
+}
@@ -0,0 +1,359 @@
+//! HTTP based alternatives to the WS connection for browsers that can't use
+//! WebSockets, e.g. because a proxy blocks upgrade requests: server-sent events
+//! (`GET {control_path}/events`) and long-polling (`GET
+//! {control_path}/poll`). Both speak the JSON protocol. Clients send their
+//! messages via `POST {control_path}/send?session=<id>&token=<token>`.
+
+use std::{
+    collections::{HashMap, hash_map::RandomState},
+    convert::Infallible,
+    hash::BuildHasher,
+    mem,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::StreamExt;
+use hyper::{Body, Request, Response, StatusCode};
+use tokio::{
+    sync::{Notify, broadcast::{Receiver, Sender}},
+    time::Instant,
+};
+
+use crate::{
+    Action, Config, SessionFilter, SessionId,
+    session::SessionGuard,
+    ws::{self, Command, PROTOCOL_VERSION},
+};
+use super::{Context, SERVER_HEADER, bad_request, query_param, user_agent};
+
+
+/// Interval in which comments are sent to keep SSE connections from timing out
+/// and to notice closed connections.
+const SSE_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// How long a poll request waits for commands before replying without any.
+const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// Polling sessions are removed if they have not polled for this long.
+const POLL_EXPIRY: Duration = Duration::from_secs(60);
+
+/// All sessions connected via long-polling.
+#[derive(Default, Clone)]
+pub(crate) struct Pollers(Arc<Mutex<HashMap<SessionId, Arc<Poller>>>>);
+
+/// Creates and checks the tokens that clients have to pass along with their
+/// session ID. Session IDs are sequential, so without tokens, any website
+/// could guess them and send messages (e.g. sync events) to other sessions via
+/// simple cross-origin `POST` requests.
+#[derive(Default)]
+pub(crate) struct Tokens(RandomState);
+
+impl Tokens {
+    /// Returns the token for `session`. As it is derived from a random key,
+    /// we don't need to store it.
+    fn token(&self, session: SessionId) -> String {
+        format!("{:016x}", self.0.hash_one(session))
+    }
+
+    /// Returns the session given via `session` query parameter if the request
+    /// also contains its token.
+    fn session(&self, req: &Request<Body>) -> Option<SessionId> {
+        let id = query_param(req, "session")?.parse::<SessionId>().ok()?;
+        let token = query_param(req, "token")?;
+        (token == self.token(id)).then_some(id)
+    }
+}
+
+/// A session connected via long-polling.
+struct Poller {
+    /// Encoded commands not yet picked up by the client.
+    queue: Mutex<Vec<String>>,
+    notify: Notify,
+    last_poll: Mutex<Instant>,
+}
+
+/// Replies with an SSE stream of commands for a new session, which is
+/// registered as long as the stream is alive.
+pub(crate) fn events(
+    req: &Request<Body>,
+    ctx: &Context,
+    actions: &Sender<(SessionFilter, Action)>,
+) -> Response<Body> {
+    let session = ctx.sessions.add(user_agent(req), query_param(req, "url"));
+    let token = ctx.tokens.token(session.id);
+    let hello = ws::hello(PROTOCOL_VERSION, session.id, Some(&token), &ctx.sessions);
+    log::debug!("Session {} connected via server-sent events", session.id);
+
+    let hello = futures::stream::once(async move { format!("data: {}\n\n", hello) });
+    let commands = futures::stream::unfold(
+        (actions.subscribe(), session),
+        |(mut receiver, session)| async move {
+            let chunk = tokio::select! {
+                action = ws::next_action(&mut receiver, &session) => {
+                    let command = Command::new(&action?).encode(Some(PROTOCOL_VERSION));
+                    format!("data: {}\n\n", command)
+                }
+                () = tokio::time::sleep(SSE_KEEP_ALIVE) => ":\n\n".to_owned(),
+            };
+            Some((chunk, (receiver, session)))
+        },
+    );
+    let body = hello.chain(commands).map(Ok::<_, Infallible>);
+
+    Response::builder()
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .header("Server", SERVER_HEADER)
+        .body(Body::wrap_stream(body))
+        .expect("bug: invalid response")
+}
+
+/// Replies with a JSON array of commands for the session given via `session`
+/// and `token` query parameters, waiting up to `POLL_TIMEOUT` for one to
+/// arrive. Without `session`, a new session is registered and the array only
+/// contains the hello message.
+pub(crate) async fn poll(
+    req: &Request<Body>,
+    ctx: &Context,
+    actions: &Sender<(SessionFilter, Action)>,
+) -> Response<Body> {
+    if query_param(req, "session").is_none() {
+        let session = ctx.sessions.add(user_agent(req), query_param(req, "url"));
+        let id = session.id;
+        log::debug!("Session {} connected via long-polling", id);
+
+        let token = ctx.tokens.token(id);
+        let hello = ws::hello(PROTOCOL_VERSION, id, Some(&token), &ctx.sessions);
+        let poller = Arc::new(Poller {
+            queue: Mutex::new(vec![]),
+            notify: Notify::new(),
+            last_poll: Mutex::new(Instant::now()),
+        });
+        ctx.pollers.0.lock().unwrap().insert(id, Arc::clone(&poller));
+        tokio::spawn(run_poller(session, poller, actions.subscribe(), ctx.pollers.clone()));
+
+        return commands_response(vec![hello]);
+    }
+
+    let poller = ctx.tokens.session(req)
+        .and_then(|id| ctx.pollers.0.lock().unwrap().get(&id).cloned());
+    let Some(poller) = poller else {
+        // The session expired or the server was restarted: the client has to
+        // start over.
+        return unknown_session();
+    };
+
+    *poller.last_poll.lock().unwrap() = Instant::now();
+    if poller.queue.lock().unwrap().is_empty() {
+        let _ = tokio::time::timeout(POLL_TIMEOUT, poller.notify.notified()).await;
+    }
+    *poller.last_poll.lock().unwrap() = Instant::now();
+
+    let commands = mem::take(&mut *poller.queue.lock().unwrap());
+    commands_response(commands)
+}
+
+/// Handles a message sent by a session connected via SSE or long-polling.
+pub(crate) async fn send(
+    req: Request<Body>,
+    config: &Config,
+    ctx: &Context,
+    actions: &Sender<(SessionFilter, Action)>,
+) -> Response<Body> {
+    let session = ctx.tokens.session(&req)
+        .filter(|&id| ctx.sessions.count(&SessionFilter::Id(id)) > 0);
+    let Some(session) = session else {
+        return unknown_session();
+    };
+
+    let Ok(body) = hyper::body::to_bytes(req.into_body()).await else {
+        return bad_request("Bad request: failed to read body\n");
+    };
+    let Ok(text) = std::str::from_utf8(&body) else {
+        return bad_request("Bad request: request body is not UTF8\n");
+    };
+
+    ws::handle_message(text, session, &ctx.sessions, actions, config.sync);
+    Response::new(Body::empty())
+}
+
+/// Queues commands for a polling session until it expires.
+async fn run_poller(
+    session: SessionGuard,
+    poller: Arc<Poller>,
+    mut receiver: Receiver<(SessionFilter, Action)>,
+    pollers: Pollers,
+) {
+    loop {
+        tokio::select! {
+            action = ws::next_action(&mut receiver, &session) => {
+                let Some(action) = action else {
+                    break;
+                };
+                let command = Command::new(&action).encode(Some(PROTOCOL_VERSION));
+                poller.queue.lock().unwrap().push(command);
+                poller.notify.notify_one();
+            }
+            () = tokio::time::sleep(POLL_EXPIRY / 4) => {
+                if poller.last_poll.lock().unwrap().elapsed() > POLL_EXPIRY {
+                    log::debug!("Polling session {} expired", session.id);
+                    break;
+                }
+            }
+        }
+    }
+
+    pollers.0.lock().unwrap().remove(&session.id);
+}
+
+fn commands_response(commands: Vec<String>) -> Response<Body> {
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-cache")
+        .header("Server", SERVER_HEADER)
+        .body(format!("[{}]", commands.join(",")).into())
+        .expect("bug: invalid response")
+}
+
+fn unknown_session() -> Response<Body> {
+    log::debug!("Replying NOT FOUND: unknown session");
+
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .header("Server", SERVER_HEADER)
+        .body("Unknown session\n".into())
+        .expect("bug: invalid response")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::{Arc, RwLock}, time::Instant};
+
+    use hyper::{Body, Method, Request, Response, StatusCode};
+    use serde_json::Value;
+    use tokio::sync::broadcast::{self, Sender};
+
+    use crate::{Action, Config, Server, SessionFilter, SessionId};
+    use super::super::{Context, proxy::ProxyContext};
+
+    fn context() -> (Context, Sender<(SessionFilter, Action)>) {
+        let config: Config = Server::bind(([127, 0, 0, 1], 4090).into())
+            .add_mount("/", "public").unwrap()
+            .validate()
+            .unwrap();
+        let ctx = Context {
+            config: Arc::new(RwLock::new(Arc::new(config))),
+            proxy: ProxyContext::new(),
+            started: Instant::now(),
+            sessions: Default::default(),
+            pollers: Default::default(),
+            tokens: Default::default(),
+        };
+        (ctx, broadcast::channel(16).0)
+    }
+
+    fn request(method: Method, uri: &str, body: &str) -> Request<Body> {
+        Request::builder().method(method).uri(uri).body(body.to_owned().into()).unwrap()
+    }
+
+    async fn json(response: Response<Body>) -> Value {
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// Registers a new polling session, returning its ID and token.
+    async fn connect(
+        ctx: &Context,
+        actions: &Sender<(SessionFilter, Action)>,
+    ) -> (SessionId, String) {
+        let req = request(Method::GET, "/~~penguin/poll?url=http%3A%2F%2Flocalhost%2F", "");
+        let messages = json(super::poll(&req, ctx, actions).await).await;
+        let hello = &messages[0];
+        assert_eq!(messages.as_array().unwrap().len(), 1);
+        assert_eq!(hello["type"], "hello");
+        let id = hello["session"].as_str().unwrap().parse().unwrap();
+        (id, hello["token"].as_str().unwrap().to_owned())
+    }
+
+    #[tokio::test]
+    async fn poll_registers_session() {
+        let (ctx, actions) = context();
+        let (id, token) = connect(&ctx, &actions).await;
+
+        assert!(ctx.pollers.0.lock().unwrap().contains_key(&id));
+        assert_eq!(ctx.sessions.count(&SessionFilter::Url("http://localhost/".into())), 1);
+        assert_eq!(token, ctx.tokens.token(id));
+        let (other, other_token) = connect(&ctx, &actions).await;
+        assert_ne!(id, other);
+        assert_ne!(token, other_token);
+    }
+
+    #[tokio::test]
+    async fn poll_delivers_queued_actions() {
+        let (ctx, actions) = context();
+        let (id, token) = connect(&ctx, &actions).await;
+
+        // Give the poller task a chance to subscribe before sending.
+        tokio::task::yield_now().await;
+        actions.send((SessionFilter::All, Action::Reload)).unwrap();
+        let uri = format!("/~~penguin/poll?session={}&token={}", id, token);
+        let messages = json(super::poll(&request(Method::GET, &uri, ""), &ctx, &actions).await)
+            .await;
+        assert_eq!(messages, serde_json::json!([{ "type": "reload" }]));
+    }
+
+    #[tokio::test]
+    async fn poll_unknown_session() {
+        let (ctx, actions) = context();
+        let (id, token) = connect(&ctx, &actions).await;
+
+        for query in [
+            format!("session={}&token=0000000000000000", id),
+            format!("session={}", id),
+            format!("session=999&token={}", token),
+            format!("session=999&token={}", ctx.tokens.token("999".parse().unwrap())),
+        ] {
+            let req = request(Method::GET, &format!("/~~penguin/poll?{}", query), "");
+            let response = super::poll(&req, &ctx, &actions).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", query);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn poll_expired_session() {
+        let (ctx, actions) = context();
+        let (id, token) = connect(&ctx, &actions).await;
+
+        tokio::time::sleep(super::POLL_EXPIRY * 2).await;
+        assert!(!ctx.pollers.0.lock().unwrap().contains_key(&id));
+        assert_eq!(ctx.sessions.count(&SessionFilter::All), 0);
+        let uri = format!("/~~penguin/poll?session={}&token={}", id, token);
+        let response = super::poll(&request(Method::GET, &uri, ""), &ctx, &actions).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn send_requires_session() {
+        let (ctx, actions) = context();
+        let (id, token) = connect(&ctx, &actions).await;
+        let config = ctx.config();
+        let send = |query: String| {
+            let req = request(
+                Method::POST,
+                &format!("/~~penguin/send?{}", query),
+                r#"{ "type": "console", "level": "log", "message": "hi" }"#,
+            );
+            let (ctx, actions, config) = (&ctx, &actions, &config);
+            async move { super::send(req, config, ctx, actions).await.status() }
+        };
+
+        assert_eq!(send(format!("session={}&token={}", id, token)).await, StatusCode::OK);
+        assert_eq!(send(format!("session={}", id)).await, StatusCode::NOT_FOUND);
+        assert_eq!(send(format!("session={}&token=abc", id)).await, StatusCode::NOT_FOUND);
+        assert_eq!(send("session=999".into()).await, StatusCode::NOT_FOUND);
+        assert_eq!(send(String::new()).await, StatusCode::NOT_FOUND);
+    }
+}
@@ -1,11 +1,12 @@
-use std::{io::{self, ErrorKind}, path::Path};
+use std::{io::{self, ErrorKind}, path::{Component, Path}};
 
 use http_range::{HttpRange, HttpRangeParseError};
 use hyper::{Body, Request, Response, header, StatusCode};
+use percent_encoding::{AsciiSet, CONTROLS, percent_decode_str, utf8_percent_encode};
 use tokio::{fs, io::{AsyncSeekExt, AsyncReadExt}};
 use tokio_util::codec::{FramedRead, BytesCodec};
 
-use crate::{inject, Config};
+use crate::{inject::{self, DocumentKind}, Config, Mount};
 use super::{bad_request, not_found, SERVER_HEADER};
 
 
@@ -15,10 +16,19 @@ pub(crate) async fn try_serve(
     req: &Request<Body>,
     config: &Config,
 ) -> Option<Response<Body>> {
+    // The URI path is percent-encoded, but we need the actual file names. If
+    // the path cannot be decoded, it cannot refer to any of our files.
+    let uri_path = match percent_decode_str(req.uri().path()).decode_utf8() {
+        Ok(path) if !path.contains('\0') => path,
+        _ => {
+            log::debug!("Request path '{}' is not valid percent-encoded UTF-8", req.uri().path());
+            return None;
+        }
+    };
+
     let (subpath, mount) = config.mounts.iter()
         .filter_map(|mount| {
-            req.uri()
-                .path()
+            uri_path
                 .strip_prefix(&mount.uri_path)
                 .map(|subpath| {
                     // Make sure that subpath never starts with `/`.
@@ -29,18 +39,20 @@ pub(crate) async fn try_serve(
         // We want the "most specific" mount, so the longest URI path wins.
         .max_by_key(|(_, mount)| mount.uri_path.len())?;
 
-    Some(serve(req, &subpath, &mount.fs_path, config).await)
+    Some(serve(req, &uri_path, &subpath, mount, config).await)
 }
 
 async fn serve(
     req: &Request<Body>,
+    uri_path: &str,
     subpath: &str,
-    fs_root: &Path,
+    mount: &Mount,
     config: &Config,
 ) -> Response<Body> {
     log::trace!("Serving request from file server...");
 
     let subpath = Path::new(subpath);
+    let fs_root = &*mount.fs_path;
     let path = fs_root.join(subpath);
 
     // Protect against directory traversal attacks.
@@ -58,16 +70,32 @@ async fn serve(
         };
     }
 
-    let canonical_req = canonicalize!(&path);
-    let canonical_root = canonicalize!(fs_root);
-    if !canonical_req.starts_with(canonical_root) {
-        log::warn!(
-            "Directory traversal attack detected ({:?} {}) -> responding BAD REQUEST",
-            req.method(),
-            req.uri().path(),
-        );
+    macro_rules! traversal_attack {
+        () => {{
+            log::warn!(
+                "Directory traversal attack detected ({:?} {}) -> responding BAD REQUEST",
+                req.method(),
+                req.uri().path(),
+            );
+
+            return bad_request("Bad request: requested file outside of served directory\n");
+        }};
+    }
 
-        return bad_request("Bad request: requested file outside of served directory\n");
+    // If symlinks should be followed, we cannot compare canonicalized paths
+    // as the symlink target might be anywhere. Instead, we make sure the
+    // request itself cannot escape the root. Otherwise, the canonical path
+    // has to be inside the root.
+    if mount.follow_symlinks {
+        if !is_contained(subpath) {
+            traversal_attack!();
+        }
+    } else {
+        let canonical_req = canonicalize!(&path);
+        let canonical_root = canonicalize!(fs_root);
+        if !canonical_req.starts_with(canonical_root) {
+            traversal_attack!();
+        }
     }
 
     // Dispatch depending on whether it's a file or directory.
@@ -81,13 +109,20 @@ async fn serve(
         serve_file(&path.join("index.html"), req, config).await
     } else {
         log::trace!("Listing contents of directory...");
-        serve_dir(req.uri().path(), &path, config)
+        serve_dir(uri_path, &path, config)
             .await
             .expect("failed to read directory contents due to IO error")
     }
 }
 
-/// Lists the contents of a directory.
+/// Characters that are percent-encoded in a single path segment, i.e. in the
+/// `href`s of the directory listing.
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ').add(b'"').add(b'#').add(b'%').add(b'&').add(b'\'').add(b'/').add(b'<')
+    .add(b'>').add(b'?').add(b'[').add(b'\\').add(b']').add(b'^').add(b'`')
+    .add(b'{').add(b'|').add(b'}');
+
+/// Lists the contents of a directory. `uri_path` is the already decoded path.
 async fn serve_dir(
     uri_path: &str,
     path: &Path,
@@ -133,15 +168,23 @@ async fn serve_dir(
     // Build list of children.
     let mut entries = String::from("\n");
     for (name, is_mount) in folders.into_iter().chain(files) {
+        // Folder names end with `/` which we must not encode.
+        let (stem, slash) = match name.strip_suffix('/') {
+            Some(stem) => (stem, "/"),
+            None => (&*name, ""),
+        };
+        let href = format!("{}{}", utf8_percent_encode(stem, PATH_SEGMENT), slash);
+
         entries.push_str(&format!(
-            "<li><a href=\"{0}\" class=\"{1}\"><code>{0}</code></a></li>\n",
-            name,
+            "<li><a href=\"{}\" class=\"{}\"><code>{}</code></a></li>\n",
+            href,
             if is_mount { "mount" } else { "real" },
+            escape_html(&name),
         ));
     }
 
     let html = DIR_LISTING_HTML
-        .replace("{{ uri_path }}", uri_path)
+        .replace("{{ uri_path }}", &escape_html(uri_path))
         .replace("{{ entries }}", &entries)
         .replace("{{ control_path }}", config.control_path());
 
@@ -154,7 +197,8 @@ async fn serve_dir(
     )
 }
 
-/// Serves a single file. If it's a HTML file, our JS code is injected.
+/// Serves a single file. If it's a HTML, XHTML or SVG file, our JS code is
+/// injected.
 async fn serve_file(
     path: &Path,
     req: &Request<Body>,
@@ -163,26 +207,32 @@ async fn serve_file(
     // TODO: maybe we should return 403 if the file can't be read due to
     // permissions? Generally, the `unwrap`s in this function are... meh.
 
-    let mime = mime_guess::from_path(&path).first();
-    if mime.as_ref().map_or(false, |mime| mime.as_ref().starts_with("text/html")) {
-        let raw = fs::read(path).await.expect("failed to read file");
-        let html = inject::into(&raw, &config);
+    let mut file = fs::File::open(path).await.expect("failed to open file");
+    let mime = match mime_from_path(path, config) {
+        Some(mime) => Some(mime),
+        None => sniff_mime(&mut file).await.expect("failed to read file"),
+    };
+
+    let document_kind = mime.as_deref().and_then(DocumentKind::from_content_type);
+    if let (Some(kind), Some(mime)) = (document_kind, &mime) {
+        let mut raw = Vec::new();
+        file.read_to_end(&mut raw).await.expect("failed to read file");
+        let html = inject::into(&raw, kind, config, None);
 
         Response::builder()
-            .header("Content-Type", "text/html")
+            .header("Content-Type", mime)
             .header("Content-Length", html.len().to_string())
             .header("Server", SERVER_HEADER)
             .body(html.into())
             .expect("bug: invalid response")
     } else {
-        let mut file = fs::File::open(path).await.expect("failed to open file");
         let file_size = file.metadata().await.expect("failed to read file metadata").len();
 
         let mut response = Response::builder()
             .header("Server", SERVER_HEADER)
             .header(header::ACCEPT_RANGES, "bytes");
         if let Some(mime) = mime {
-            response = response.header("Content-Type", mime.to_string());
+            response = response.header("Content-Type", mime);
         }
 
         if let Some(range_header) = req.headers().get(header::RANGE) {
@@ -228,3 +278,313 @@ async fn serve_file(
         }
     }
 }
+
+/// MIME types for extensions that `mime_guess` does not know or gets wrong
+/// for the web. Can be overridden by `Config::mime_types`.
+const DEFAULT_MIME_TYPES: &[(&str, &str)] = &[
+    ("wasm", "application/wasm"),
+    ("mjs", "text/javascript"),
+    ("webmanifest", "application/manifest+json"),
+    ("glb", "model/gltf-binary"),
+    ("gltf", "model/gltf+json"),
+    ("ktx2", "image/ktx2"),
+];
+
+/// Determines the MIME type (for the `Content-Type` header) of a file from its
+/// extension. Returns `None` if that's not possible.
+fn mime_from_path(path: &Path, config: &Config) -> Option<String> {
+    let extension = path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase());
+
+    let mime = extension.as_ref()
+        .and_then(|ext| {
+            config.mime_types.get(ext).cloned().or_else(|| {
+                DEFAULT_MIME_TYPES.iter()
+                    .find(|(default_ext, _)| default_ext == ext)
+                    .map(|(_, mime)| mime.to_string())
+            })
+        })
+        .or_else(|| mime_guess::from_path(path).first().map(|mime| mime.to_string()))?;
+
+    Some(with_charset(mime))
+}
+
+/// Determines the MIME type of a file by looking at its first bytes. The
+/// file cursor is reset to the start afterwards.
+async fn sniff_mime(file: &mut fs::File) -> Result<Option<String>, io::Error> {
+    let mut buf = Vec::with_capacity(8192);
+    (&mut *file).take(8192).read_to_end(&mut buf).await?;
+    file.seek(io::SeekFrom::Start(0)).await?;
+
+    Ok(infer::get(&buf).map(|ty| with_charset(ty.mime_type().to_owned())))
+}
+
+/// Adds `charset=utf-8` to textual MIME types without charset parameter.
+fn with_charset(mime: String) -> String {
+    let essence = mime.split(';').next().unwrap_or("").trim();
+    let is_textual = essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || ["application/javascript", "application/json", "application/xml"].contains(&essence);
+
+    if is_textual && !mime.to_lowercase().contains("charset=") {
+        mime + "; charset=utf-8"
+    } else {
+        mime
+    }
+}
+
+/// Escapes all characters with special meaning in HTML.
+pub(crate) fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Returns `true` if `subpath` only consists of normal components, i.e. if
+/// joining it onto a directory results in a path inside that directory
+/// (ignoring symlinks).
+fn is_contained(subpath: &Path) -> bool {
+    subpath.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use hyper::{Body, Request, StatusCode};
+
+    use crate::{Config, Mount, Server};
+
+
+    /// Creates a fresh, empty directory for a test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("penguin-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn config(root: &Path) -> Config {
+        Server::bind(([127, 0, 0, 1], 4090).into())
+            .add_mount("/", root)
+            .unwrap()
+            .validate()
+            .unwrap()
+    }
+
+    async fn get(config: &Config, path: &str) -> Option<(StatusCode, String)> {
+        let req = Request::get(path).body(Body::empty()).unwrap();
+        let response = super::try_serve(&req, config).await?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        Some((status, String::from_utf8_lossy(&body).into_owned()))
+    }
+
+    #[test]
+    fn charset() {
+        use super::with_charset;
+
+        assert_eq!(with_charset("text/css".into()), "text/css; charset=utf-8");
+        assert_eq!(with_charset("text/javascript".into()), "text/javascript; charset=utf-8");
+        assert_eq!(with_charset("application/json".into()), "application/json; charset=utf-8");
+        assert_eq!(
+            with_charset("application/manifest+json".into()),
+            "application/manifest+json; charset=utf-8",
+        );
+        assert_eq!(with_charset("text/plain; charset=latin1".into()), "text/plain; charset=latin1");
+        assert_eq!(with_charset("application/wasm".into()), "application/wasm");
+        assert_eq!(with_charset("image/png".into()), "image/png");
+    }
+
+    #[tokio::test]
+    async fn mime_types() {
+        let dir = test_dir("mime-types");
+        for name in ["a.wasm", "a.mjs", "a.webmanifest", "a.ktx2", "a.css", "a.GLB", "a.custom"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        std::fs::write(dir.join("png-without-extension"), b"\x89PNG\r\n\x1a\n\0\0\0\0").unwrap();
+        std::fs::write(dir.join("unknown"), "foo").unwrap();
+
+        let config = Server::bind(([127, 0, 0, 1], 4090).into())
+            .add_mount("/", &dir)
+            .unwrap()
+            .add_mime_type(".glb", "model/x-custom")
+            .add_mime_type("custom", "text/x-custom")
+            .validate()
+            .unwrap();
+
+        async fn content_type(config: &Config, path: &str) -> Option<String> {
+            let req = Request::get(path).body(Body::empty()).unwrap();
+            let response = super::try_serve(&req, config).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            response.headers()
+                .get("Content-Type")
+                .map(|v| v.to_str().unwrap().to_owned())
+        }
+
+        assert_eq!(content_type(&config, "/a.wasm").await.unwrap(), "application/wasm");
+        assert_eq!(content_type(&config, "/a.mjs").await.unwrap(), "text/javascript; charset=utf-8");
+        assert_eq!(
+            content_type(&config, "/a.webmanifest").await.unwrap(),
+            "application/manifest+json; charset=utf-8",
+        );
+        assert_eq!(content_type(&config, "/a.ktx2").await.unwrap(), "image/ktx2");
+        assert_eq!(content_type(&config, "/a.css").await.unwrap(), "text/css; charset=utf-8");
+        assert_eq!(content_type(&config, "/a.GLB").await.unwrap(), "model/x-custom");
+        assert_eq!(content_type(&config, "/a.custom").await.unwrap(), "text/x-custom; charset=utf-8");
+        assert_eq!(content_type(&config, "/png-without-extension").await.unwrap(), "image/png");
+        assert_eq!(content_type(&config, "/unknown").await, None);
+
+        let invalid = Server::bind(([127, 0, 0, 1], 4090).into())
+            .add_mount("/", &dir)
+            .unwrap()
+            .add_mime_type("foo", "not a MIME type")
+            .validate();
+        assert!(matches!(invalid, Err(crate::ConfigError::InvalidMimeType { .. })));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unusual_file_names() {
+        let dir = test_dir("unusual-file-names");
+        let root = dir.join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::create_dir(root.join("sub dir")).unwrap();
+        std::fs::write(dir.join("secret.txt"), "secret").unwrap();
+        for name in ["My Report.pdf", "über.txt", "a#b?c.txt", "100%.txt", "<b>&amp;.txt"] {
+            std::fs::write(root.join(name), name).unwrap();
+        }
+        let config = config(&root);
+
+        #[track_caller]
+        fn assert_ok(actual: Option<(StatusCode, String)>, expected_body: &str) {
+            assert_eq!(actual, Some((StatusCode::OK, expected_body.into())));
+        }
+
+        assert_ok(get(&config, "/My%20Report.pdf").await, "My Report.pdf");
+        assert_ok(get(&config, "/%C3%BCber.txt").await, "über.txt");
+        assert_ok(get(&config, "/a%23b%3Fc.txt").await, "a#b?c.txt");
+        assert_ok(get(&config, "/100%25.txt").await, "100%.txt");
+        assert_ok(get(&config, "/%3Cb%3E&amp;.txt").await, "<b>&amp;.txt");
+
+        // Invalid UTF-8 and encoded traversal attempts.
+        assert_eq!(get(&config, "/%FF.txt").await, None);
+        assert_eq!(get(&config, "/%00.txt").await, None);
+        let (status, _) = get(&config, "/%2E%2E/secret.txt").await.unwrap();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // Directory listing
+        let (status, listing) = get(&config, "/").await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        for expected in [
+            r#"href="My%20Report.pdf""#,
+            r#"href="%C3%BCber.txt""#,
+            r#"href="a%23b%3Fc.txt""#,
+            r#"href="100%25.txt""#,
+            r#"href="%3Cb%3E%26amp;.txt""#,
+            r#"href="sub%20dir/""#,
+            "<code>&lt;b&gt;&amp;amp;.txt</code>",
+            "<code>über.txt</code>",
+        ] {
+            assert!(listing.contains(expected), "listing does not contain {}", expected);
+        }
+
+        let (_, listing) = get(&config, "/sub%20dir/").await.unwrap();
+        assert!(listing.contains("<code>/sub dir/</code>"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn follow_symlinks() {
+        let dir = test_dir("follow-symlinks");
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(dir.join("outside.txt"), "outside").unwrap();
+        std::fs::write(dir.join("secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink(dir.join("outside.txt"), root.join("link.txt")).unwrap();
+
+        let config = |follow_symlinks| {
+            let mount = Mount { uri_path: "/".into(), fs_path: root.clone(), follow_symlinks };
+            Server::bind(([127, 0, 0, 1], 4090).into())
+                .mount(mount)
+                .unwrap()
+                .validate()
+                .unwrap()
+        };
+        let following = config(true);
+        let not_following = config(false);
+
+        assert_eq!(
+            get(&following, "/link.txt").await,
+            Some((StatusCode::OK, "outside".into())),
+        );
+        let (status, _) = get(&not_following, "/link.txt").await.unwrap();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        for config in [&following, &not_following] {
+            for path in ["/%2E%2E/secret.txt", "/sub/%2E%2E/%2E%2E/secret.txt"] {
+                let (status, _) = get(config, path).await.unwrap();
+                assert_eq!(status, StatusCode::BAD_REQUEST, "{}", path);
+            }
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn svg_page() {
+        let dir = test_dir("svg-page");
+        std::fs::write(
+            dir.join("image.svg"),
+            r#"<svg xmlns="http://www.w3.org/2000/svg"><circle r="5"/></svg>"#,
+        ).unwrap();
+        let config = config(&dir);
+
+        let (status, body) = get(&config, "/image.svg").await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        let script_tag = r#"<script href="/~~penguin/client.js"></script>"#;
+        assert!(body.ends_with(&format!(r#"<circle r="5"/>{}</svg>"#, script_tag)));
+
+        // SVG documents have no `body` and `document.createElement` does not
+        // create HTML elements there, so the client must not rely on either.
+        let script = crate::inject::script(&config);
+        assert!(!script.contains("document.body."));
+        assert!(!script.contains("document.createElement("));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn contained_paths() {
+        use super::is_contained;
+
+        assert!(is_contained(Path::new("")));
+        assert!(is_contained(Path::new("foo")));
+        assert!(is_contained(Path::new("foo/bar.html")));
+        assert!(is_contained(Path::new("./foo/./bar")));
+        assert!(is_contained(Path::new("node_modules/.pnpm/foo")));
+        assert!(is_contained(Path::new("foo..bar/baz")));
+
+        assert!(!is_contained(Path::new("..")));
+        assert!(!is_contained(Path::new("../secret")));
+        assert!(!is_contained(Path::new("foo/../../secret")));
+        assert!(!is_contained(Path::new("foo/../bar")));
+        assert!(!is_contained(Path::new("/etc/passwd")));
+    }
+}
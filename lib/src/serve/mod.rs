@@ -1,26 +1,47 @@
-use std::{convert::Infallible, future::Future, panic::AssertUnwindSafe, sync::Arc};
+use std::{
+    convert::Infallible,
+    future::Future,
+    panic::AssertUnwindSafe,
+    sync::{Arc, RwLock},
+    time::Instant,
+};
 
 use futures::FutureExt;
 use hyper::{
     Body, Method, Request, Response, Server, StatusCode,
+    header::{self, HeaderName, HeaderValue},
     http::uri::PathAndQuery,
     service::{make_service_fn, service_fn},
 };
 use tokio::sync::broadcast::Sender;
 
 use crate::serve::proxy::ProxyContext;
-use super::{Action, Config};
+use crate::session::Sessions;
+use super::{Action, Config, Diagnostic, SessionFilter};
 
+mod api;
+mod cors;
+mod fallback;
 mod fs;
 mod proxy;
 
+pub(crate) use fs::escape_html;
+
 
-pub(crate) async fn run(config: Config, actions: Sender<Action>) -> Result<(), hyper::Error> {
-    let addr = config.bind_addr;
+pub(crate) async fn run(
+    config: Arc<RwLock<Arc<Config>>>,
+    actions: Sender<(SessionFilter, Action)>,
+    sessions: Arc<Sessions>,
+) -> Result<(), hyper::Error> {
+    let addr = config.read().unwrap().bind_addr;
 
     let ctx = Arc::new(Context {
         config,
         proxy: ProxyContext::new(),
+        started: Instant::now(),
+        sessions,
+        pollers: Default::default(),
+        tokens: Default::default(),
     });
     let make_service = make_service_fn(move |_| {
         let ctx = Arc::clone(&ctx);
@@ -78,15 +99,27 @@ async fn handle_internal_errors(
 }
 
 pub(crate) struct Context {
-    config: Config,
+    /// Can be replaced via `Controller::update_config` at any time.
+    config: Arc<RwLock<Arc<Config>>>,
     proxy: ProxyContext,
+    started: Instant,
+    sessions: Arc<Sessions>,
+    pollers: fallback::Pollers,
+    tokens: fallback::Tokens,
+}
+
+impl Context {
+    /// Returns the current configuration.
+    fn config(&self) -> Arc<Config> {
+        Arc::clone(&self.config.read().unwrap())
+    }
 }
 
 /// Handles a single incoming request.
 async fn handle(
     req: Request<Body>,
     ctx: Arc<Context>,
-    actions: Sender<Action>,
+    actions: Sender<(SessionFilter, Action)>,
 ) -> Response<Body> {
     log::trace!(
         "Incoming request: {:?} {}",
@@ -94,32 +127,88 @@ async fn handle(
         req.uri().path_and_query().unwrap_or(&PathAndQuery::from_static("/")),
     );
 
-    if req.uri().path().starts_with(&ctx.config.control_path) {
-        handle_control(req, &ctx.config, actions).await
-    } else if let Some(response) = fs::try_serve(&req, &ctx.config).await {
-        response
-    } else if let Some(proxy) = &ctx.config.proxy {
-        proxy::forward(req, proxy, &ctx, actions).await
+    // The same configuration is used for the whole request, even if it is
+    // updated in the meantime.
+    let config = ctx.config();
+    let path = req.uri().path().to_owned();
+    if path.starts_with(&config.control_path) {
+        return handle_control(req, &config, &ctx, actions).await;
+    }
+
+    let cors = config.cors.as_ref();
+    if let Some(response) = cors.and_then(|cors| cors::preflight(&req, cors)) {
+        return response;
+    }
+    let origin = req.headers().get(header::ORIGIN).cloned();
+
+    let response = if let Some(response) = fs::try_serve(&req, &config).await {
+        add_custom_headers(response, &path, &config)
+    } else if let Some(proxy) = &config.proxy {
+        let response = proxy::forward(req, proxy, &config, &ctx, actions).await;
+        if config.proxy_headers {
+            add_custom_headers(response, &path, &config)
+        } else {
+            response
+        }
     } else {
-        not_found(&ctx.config)
+        not_found(&config)
+    };
+
+    match cors {
+        Some(cors) => cors::add_headers(response, origin.as_ref(), cors),
+        None => response,
+    }
+}
+
+/// Adds the headers configured via `Builder::header` matching `path`.
+fn add_custom_headers(
+    mut response: Response<Body>,
+    path: &str,
+    config: &Config,
+) -> Response<Body> {
+    for header in config.headers.iter().filter(|h| h.matches(path)) {
+        // Name and value were checked in `Builder::validate`.
+        let name = HeaderName::from_bytes(header.name.as_bytes()).unwrap();
+        let value = HeaderValue::from_str(&header.value).unwrap();
+        response.headers_mut().insert(name, value);
     }
+
+    response
 }
 
 /// Handles "control requests", i.e. request to the control path.
 async fn handle_control(
     req: Request<Body>,
     config: &Config,
-    actions: Sender<Action>,
+    ctx: &Context,
+    actions: Sender<(SessionFilter, Action)>,
 ) -> Response<Body> {
     log::trace!("Handling request to HTTP control API...");
 
     if hyper_tungstenite::is_upgrade_request(&req) {
         log::trace!("Handling WS upgrade request...");
+        let user_agent = user_agent(&req);
+
+        // The script passes the URL of the page and the protocol version it
+        // speaks via query parameters. Clients without the latter use the
+        // legacy text protocol.
+        let url = query_param(&req, "url");
+        let protocol = query_param(&req, "protocol")
+            .and_then(|v| v.parse().ok())
+            .filter(|&v| v >= 1);
+
         match hyper_tungstenite::upgrade(req, None) {
             Ok((response, websocket)) => {
                 // Spawn a task to handle the websocket connection.
-                let receiver = actions.subscribe();
-                tokio::spawn(crate::ws::handle_connection(websocket, receiver));
+                tokio::spawn(crate::ws::handle_connection(
+                    websocket,
+                    actions,
+                    Arc::clone(&ctx.sessions),
+                    user_agent,
+                    url,
+                    protocol,
+                    config.sync,
+                ));
 
                 // Return the response so the spawned future can continue.
                 response
@@ -131,6 +220,11 @@ async fn handle_control(
         }
     } else {
         let subpath = req.uri().path().strip_prefix(&config.control_path).unwrap();
+        if let Some(api_path) = subpath.strip_prefix("/api/v1") {
+            let api_path = api_path.to_owned();
+            return api::handle(req, &api_path, config, ctx, actions).await;
+        }
+
         match (req.method(), subpath) {
             (&Method::GET, "/client.js") => {
                 Response::builder()
@@ -139,12 +233,17 @@ async fn handle_control(
                     .unwrap()
             }
 
+            (&Method::GET, "/events") => fallback::events(&req, ctx, &actions),
+            (&Method::GET, "/poll") => fallback::poll(&req, ctx, &actions).await,
+            (&Method::POST, "/send") => fallback::send(req, config, ctx, &actions).await,
+
             (&Method::POST, "/reload") => {
                 // We ignore errors here: if there are no receivers, so be it.
                 // Although we might want to include the number of receivers in
                 // the event.
                 log::debug!("Received reload request via HTTP control API");
-                let _ = actions.send(Action::Reload);
+                ctx.sessions.note_reload();
+                let _ = actions.send((SessionFilter::All, Action::Reload));
 
                 Response::new(Body::empty())
             }
@@ -162,7 +261,34 @@ async fn handle_control(
                         // Although we might want to include the number of receivers in
                         // the event.
                         log::debug!("Received message request via HTTP control API");
-                        let _ = actions.send(Action::Message(s.into()));
+                        let _ = actions.send((SessionFilter::All, Action::Message(s.into())));
+
+                        Response::new(Body::empty())
+                    }
+                }
+            }
+
+            (&Method::POST, "/clear") => {
+                log::debug!("Received clear request via HTTP control API");
+                let _ = actions.send((SessionFilter::All, Action::ClearMessage));
+
+                Response::new(Body::empty())
+            }
+
+            (&Method::POST, "/error") => {
+                let (_, body) = req.into_parts();
+                let body = hyper::body::to_bytes(body)
+                    .await
+                    .expect("failed to download error body");
+
+                match serde_json::from_slice::<Diagnostic>(&body) {
+                    Err(e) => {
+                        log::debug!("Invalid diagnostic sent to HTTP control API: {}", e);
+                        bad_request("Bad request: request body is not a valid diagnostic JSON\n")
+                    }
+                    Ok(diagnostic) => {
+                        log::debug!("Received error request via HTTP control API");
+                        let _ = actions.send((SessionFilter::All, Action::Error(diagnostic)));
 
                         Response::new(Body::empty())
                     }
@@ -174,6 +300,24 @@ async fn handle_control(
     }
 }
 
+fn user_agent(req: &Request<Body>) -> Option<String> {
+    req.headers()
+        .get(hyper::header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned)
+}
+
+/// Returns the percent-decoded value of the query parameter `key`.
+fn query_param(req: &Request<Body>, key: &str) -> Option<String> {
+    req.uri()
+        .query()
+        .into_iter()
+        .flat_map(|q| q.split('&'))
+        .find_map(|pair| pair.strip_prefix(key)?.strip_prefix('='))
+        .and_then(|v| percent_encoding::percent_decode_str(v).decode_utf8().ok())
+        .map(|v| v.into_owned())
+}
+
 fn bad_request(msg: &'static str) -> Response<Body> {
     log::debug!("Replying BAD REQUEST: {}", msg);
 
@@ -200,3 +344,37 @@ fn not_found(config: &Config) -> Response<Body> {
 }
 
 const SERVER_HEADER: &str = concat!("Penguin v", env!("CARGO_PKG_VERSION"));
+
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Body, Response};
+
+    use crate::Server;
+    use super::add_custom_headers;
+
+    #[test]
+    fn custom_headers() {
+        let config = Server::bind(([127, 0, 0, 1], 4090).into())
+            .add_mount("/", "public").unwrap()
+            .header("*", "Cross-Origin-Opener-Policy", "same-origin")
+            .header("/assets/*", "Cache-Control", "max-age=60")
+            .header("*.wasm", "Cache-Control", "no-store")
+            .validate()
+            .unwrap();
+        let headers = |path| {
+            let response = Response::builder()
+                .header("Cache-Control", "no-cache")
+                .body(Body::empty())
+                .unwrap();
+            add_custom_headers(response, path, &config).headers().clone()
+        };
+
+        let index = headers("/index.html");
+        assert_eq!(index["Cross-Origin-Opener-Policy"], "same-origin");
+        assert_eq!(index["Cache-Control"], "no-cache");
+        assert_eq!(headers("/assets/logo.png")["Cache-Control"], "max-age=60");
+        assert_eq!(headers("/assets/app.wasm").get_all("Cache-Control").iter().count(), 1);
+        assert_eq!(headers("/assets/app.wasm")["Cache-Control"], "no-store");
+    }
+}
@@ -17,7 +17,11 @@ use hyper::{
 use hyper_tls::HttpsConnector;
 use tokio::sync::broadcast::Sender;
 
-use crate::{Action, Config, ProxyTarget, inject};
+use crate::{
+    Action, Config, ProxyTarget, SessionFilter,
+    inject::{self, DocumentKind},
+    session::Sessions,
+};
 
 use super::{Context, SERVER_HEADER};
 
@@ -45,8 +49,9 @@ impl ProxyContext {
 pub(crate) async fn forward(
     mut req: Request<Body>,
     target: &ProxyTarget,
+    config: &Config,
     ctx: &Context,
-    actions: Sender<Action>,
+    actions: Sender<(SessionFilter, Action)>,
 ) -> Response<Body> {
     adjust_request(&mut req, target);
     let uri = req.uri().clone();
@@ -54,12 +59,12 @@ pub(crate) async fn forward(
     log::trace!("Forwarding request to proxy target {}", uri);
     let client = Client::builder().build::<_, hyper::Body>(HttpsConnector::new());
     match client.request(req).await {
-        Ok(response) => adjust_response(response, ctx, &uri, target, &ctx.config).await,
+        Ok(response) => adjust_response(response, &uri, target, config).await,
         Err(e) => {
             log::warn!("Failed to reach proxy target '{}': {}", uri, e);
             let msg = format!("Failed to reach {}\n\n{}", uri, e);
-            start_polling(&ctx.proxy, target, actions);
-            gateway_error(&msg, e, &ctx.config)
+            start_polling(&ctx.proxy, target, Arc::clone(&ctx.sessions), actions);
+            gateway_error(&msg, e, config)
         }
     }
 }
@@ -109,15 +114,14 @@ fn adjust_request(req: &mut Request<Body>, target: &ProxyTarget) {
 /// https://almanac.httparchive.org/en/2019/compression
 const SUPPORTED_COMPRESSIONS: &[&str] = &["gzip", "br", "identity"];
 
-fn download_body_error(e: hyper::Error, uri: &Uri, ctx: &Context) -> Response<Body> {
+fn download_body_error(e: hyper::Error, uri: &Uri, config: &Config) -> Response<Body> {
     log::warn!("Failed to download full response from proxy target");
     let msg = format!("Failed to download response from {}\n\n{}", uri, e);
-    return gateway_error(&msg, e, &ctx.config);
+    return gateway_error(&msg, e, config);
 }
 
 async fn adjust_response(
     mut response: Response<Body>,
-    ctx: &Context,
     uri: &Uri,
     target: &ProxyTarget,
     config: &Config,
@@ -133,14 +137,13 @@ async fn adjust_response(
     while !body.is_end_stream() && body_start.len() < 512 {
         match body.data().await {
             None => break,
-            Some(Err(e)) => return download_body_error(e, uri, ctx),
+            Some(Err(e)) => return download_body_error(e, uri, config),
             Some(Ok(bytes)) => body_start.extend_from_slice(&bytes),
         }
     }
 
-    let html_content_type = parts.headers.get(header::CONTENT_TYPE).map(|v| {
-        v.as_bytes().starts_with(b"text/html")
-            || v.as_bytes().starts_with(b"application/xhtml+xml")
+    let document_kind = parts.headers.get(header::CONTENT_TYPE).map(|v| {
+        v.to_str().ok().and_then(DocumentKind::from_content_type)
     });
     let looks_like_html = body_start.iter().all(|b| *b != 0)
         && infer::text::is_html(&body_start);
@@ -160,60 +163,65 @@ async fn adjust_response(
         };
     }
 
-    // Determine if we should treat this as HTML (i.e. inject our script).
-    let adjust_body = match (html_content_type, looks_like_html) {
+    // Determine if we should inject our script.
+    let inject_kind = match (document_kind, looks_like_html) {
         (None, true) => {
             warn_once!("Proxy response to '{uri_pq}' looks like HTML, but no 'Content-Type' \
                 header exists. I will treat it as HTML (injecting reload script), but setting \
                 the correct 'Content-Type' header is recommended.",
             );
-            true
+            Some(DocumentKind::Html)
         }
-        (None, false) => false,
-        (Some(true), true) => true,
-        (Some(false), true) => {
+        (None, false) => None,
+        (Some(None), true) => {
             let header_bytes = parts.headers.get(header::CONTENT_TYPE).unwrap().as_bytes();
             warn_once!("Proxy response to '{uri_pq}' looks like HTML, but the 'Content-Type' \
                 header indicates otherwise: '{}'. Not injecting reload script.",
                 String::from_utf8_lossy(header_bytes),
             );
-            false
+            None
         }
-        (Some(v), false) => v,
+        (Some(kind), _) => kind,
     };
 
-    if !adjust_body {
+    let Some(kind) = inject_kind else {
         let recombined_body = Body::wrap_stream(
             futures::stream::once(async { Ok(Bytes::from(body_start)) }).chain(body)
         );
 
         return Response::from_parts(parts, recombined_body);
-    }
+    };
 
 
-    log::trace!("Response from proxy is HTML: injecting script");
+    log::trace!("Response from proxy is {:?}: injecting script", kind);
 
     // The response is HTML: we need to download it completely and
     // inject our script.
     while let Some(buf) = body.data().await {
         match buf {
             Ok(buf) => body_start.extend_from_slice(&buf),
-            Err(e) => return download_body_error(e, uri, ctx),
+            Err(e) => return download_body_error(e, uri, config),
         }
     }
     let body = body_start;
 
+    // If the page uses a nonce for scripts, our script needs it as well.
+    let nonce = parts.headers.get_all(header::CONTENT_SECURITY_POLICY)
+        .iter()
+        .find_map(csp_nonce);
+    let nonce = nonce.as_deref();
+
     // Uncompress if necessary. All this allocates more than necessary, but I'd
     // rather keep easier code in this case, as performance is unlikely to
     // matter.
     let new_body = match parts.headers.get(header::CONTENT_ENCODING).map(|v| v.as_bytes()) {
-        None => Bytes::from(inject::into(&body, &ctx.config)),
+        None => Bytes::from(inject::into(&body, kind, config, nonce)),
 
         Some(b"gzip") => {
             let mut decompressed = Vec::new();
             flate2::read::GzDecoder::new(&*body).read_to_end(&mut decompressed)
                 .expect("unexpected error while decompressing GZIP");
-            let injected = inject::into(&decompressed, &ctx.config);
+            let injected = inject::into(&decompressed, kind, config, nonce);
             let mut out = Vec::new();
             flate2::read::GzEncoder::new(&*injected, flate2::Compression::best())
                 .read_to_end(&mut out)
@@ -225,7 +233,7 @@ async fn adjust_response(
             let mut decompressed = Vec::new();
             brotli::BrotliDecompress(&mut &*body, &mut decompressed)
                 .expect("unexpected error while decompressing Brotli");
-            let injected = inject::into(&decompressed, &ctx.config);
+            let injected = inject::into(&decompressed, kind, config, nonce);
             let mut out = Vec::new();
             brotli::BrotliCompress(&mut &*injected, &mut out, &Default::default())
                 .expect("unexpected error while compressing Brotli");
@@ -258,6 +266,31 @@ async fn adjust_response(
     Response::from_parts(parts, new_body.into())
 }
 
+/// Returns the nonce that scripts need according to the given CSP header or
+/// `None` if the CSP does not contain a script nonce. The nonce is taken from
+/// the directive that governs `<script>` elements.
+fn csp_nonce(header: &HeaderValue) -> Option<String> {
+    let directives = header.to_str().ok()?
+        .split(';')
+        .filter_map(|part| {
+            let mut split = part.split_whitespace();
+            Some((split.next()?.to_ascii_lowercase(), split))
+        })
+        .collect::<Vec<_>>();
+
+    let sources = ["script-src-elem", "script-src", "default-src"].iter()
+        .find_map(|name| directives.iter().find(|(n, _)| n == name))?
+        .1
+        .clone();
+
+    // Nonces are base64 encoded, so we reject everything else to make sure we
+    // don't break our `<script>` tag.
+    sources
+        .filter_map(|src| src.strip_prefix("'nonce-")?.strip_suffix('\''))
+        .find(|nonce| nonce.bytes().all(|b| b.is_ascii_alphanumeric() || b"+/=-_".contains(&b)))
+        .map(|nonce| nonce.to_owned())
+}
+
 /// We inject our own JS that connects via WS to the penguin server. These two
 /// things need to be allowed by the Content-Security-Policy. Usually they are,
 /// but in some cases we need to modify that header to allow for it.
@@ -403,7 +436,12 @@ fn gateway_error(msg: &str, e: hyper::Error, config: &Config) -> Response<Body>
 /// Regularly polls the proxy target until it is reachable again. Once it is, it
 /// sends a reload action and stops. Makes sure (via `ctx`) that just one
 /// polling instance exists per penguin server.
-fn start_polling(ctx: &ProxyContext, target: &ProxyTarget, actions: Sender<Action>) {
+fn start_polling(
+    ctx: &ProxyContext,
+    target: &ProxyTarget,
+    sessions: Arc<Sessions>,
+    actions: Sender<(SessionFilter, Action)>,
+) {
     // We only need one task polling the target.
     let is_polling = Arc::clone(&ctx.is_polling_target);
     if is_polling.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
@@ -431,7 +469,8 @@ fn start_polling(ctx: &ProxyContext, target: &ProxyTarget, actions: Sender<Actio
             log::trace!("Trying to connect to '{}' again", uri);
             if client.get(uri.clone()).await.is_ok() {
                 log::debug!("Reconnected to proxy target, reloading all active browser sessions");
-                let _ = actions.send(Action::Reload);
+                sessions.note_reload();
+                let _ = actions.send((SessionFilter::All, Action::Reload));
                 is_polling.store(false, Ordering::SeqCst);
                 break;
             }
@@ -479,6 +518,25 @@ mod tests {
         assert_eq!(filter("br;q=1.0, deflate;q=0.5, gzip;q=0.8, *;q=0.1"), "br;q=1.0, gzip;q=0.8");
     }
 
+    #[test]
+    fn nonce_from_csp() {
+        fn nonce(csp: &str) -> Option<String> {
+            super::csp_nonce(&hyper::header::HeaderValue::from_str(csp).unwrap())
+        }
+
+        assert_eq!(nonce("default-src 'self'"), None);
+        assert_eq!(nonce("script-src 'nonce-abc123'"), Some("abc123".into()));
+        assert_eq!(nonce("default-src 'nonce-R2Vu+/Z='"), Some("R2Vu+/Z=".into()));
+        assert_eq!(
+            nonce("default-src 'self'; script-src 'strict-dynamic' 'nonce-xyz'"),
+            Some("xyz".into()),
+        );
+        assert_eq!(nonce("script-src-elem 'nonce-a'; script-src 'nonce-b'"), Some("a".into()));
+        assert_eq!(nonce("script-src 'self'; default-src 'nonce-abc'"), None);
+        assert_eq!(nonce("style-src 'nonce-abc'"), None);
+        assert_eq!(nonce("script-src 'nonce-a\"b'"), None);
+    }
+
     #[test]
     fn modify_csp() {
         #[track_caller]
@@ -0,0 +1,372 @@
+use std::{
+    collections::{HashMap, hash_map::RandomState},
+    fmt,
+    hash::{BuildHasher, Hasher},
+    num::ParseIntError,
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}},
+    task,
+    time::SystemTime,
+};
+
+use futures::Stream;
+use tokio::sync::broadcast::{self, Receiver, Sender, error::RecvError};
+
+
+/// Identifies a connected browser session. IDs are never reused by a server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SessionId(u64);
+
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for SessionId {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self)
+    }
+}
+
+/// Information about a connected browser session, see
+/// [`Controller::sessions`][crate::Controller::sessions].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionInfo {
+    /// Unique ID of this session.
+    pub id: SessionId,
+
+    /// Value of the `User-Agent` header sent when the session connected.
+    pub user_agent: Option<String>,
+
+    /// URL of the page the session is on. Updated when the page changes it
+    /// without loading a new page, e.g. via `history.pushState`.
+    pub url: Option<String>,
+
+    /// When the session connected to the server.
+    pub connected_since: SystemTime,
+}
+
+/// Selects the browser sessions a command is sent to, see
+/// [`Controller::select`][crate::Controller::select].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionFilter {
+    /// All sessions.
+    All,
+
+    /// Only the session with the given ID.
+    Id(SessionId),
+
+    /// All sessions whose URL matches the given pattern. The pattern has to
+    /// match the whole URL, but may contain `*` which matches any sequence of
+    /// characters, e.g. `http://localhost:4090/docs/*`.
+    Url(String),
+}
+
+impl SessionFilter {
+    /// Returns whether `session` is selected by this filter.
+    pub fn matches(&self, session: &SessionInfo) -> bool {
+        match self {
+            Self::All => true,
+            Self::Id(id) => session.id == *id,
+            Self::Url(pattern) => session.url.as_deref()
+                .is_some_and(|url| wildcard_match(pattern, url)),
+        }
+    }
+}
+
+/// Something that happened with a browser session, see
+/// [`Controller::subscribe_events`][crate::Controller::subscribe_events].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Event {
+    /// A browser session connected to the server.
+    ClientConnected(SessionInfo),
+
+    /// A browser session disconnected, e.g. because the page was closed or
+    /// reloaded.
+    ClientDisconnected(SessionInfo),
+
+    /// The connection to a browser failed or had problems. `session` is `None`
+    /// if the error happened before the session was established.
+    ClientError {
+        session: Option<SessionId>,
+        message: String,
+    },
+
+    /// A browser session logged something to its console or had an uncaught
+    /// error (reported with level `Error`). Only sent if enabled via
+    /// [`Builder::forward_console`][crate::Builder::forward_console].
+    Console {
+        session: SessionId,
+        level: ConsoleLevel,
+        message: String,
+    },
+}
+
+/// Level of a [`Event::Console`] message, corresponding to the `console`
+/// method that was called in the browser.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConsoleLevel {
+    Debug,
+    Log,
+    Info,
+    Warn,
+    Error,
+}
+
+/// A stream of [`Event`]s, obtained via
+/// [`Controller::subscribe_events`][crate::Controller::subscribe_events].
+///
+/// Only events that happen after subscribing are yielded. If events are not
+/// consumed quickly enough, the oldest ones are dropped. The stream ends once
+/// the server and all controllers are dropped.
+pub struct EventStream {
+    inner: Pin<Box<dyn Stream<Item = Event> + Send>>,
+}
+
+impl EventStream {
+    fn new(receiver: Receiver<Event>) -> Self {
+        let inner = futures::stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some((event, receiver)),
+                    Err(RecvError::Lagged(skipped)) => {
+                        log::warn!("Event stream too slow: skipped {} events", skipped);
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+
+        Self { inner: Box::pin(inner) }
+    }
+
+    /// Returns the next event or `None` if the server was dropped. Same as
+    /// `StreamExt::next`, but without needing to import a trait.
+    pub async fn recv(&mut self) -> Option<Event> {
+        futures::StreamExt::next(self).await
+    }
+}
+
+impl Stream for EventStream {
+    type Item = Event;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> task::Poll<Option<Event>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
+impl fmt::Debug for EventStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad("EventStream(_)")
+    }
+}
+
+const EVENT_CHANNEL_SIZE: usize = 64;
+
+/// The registry of all connected browser sessions.
+#[derive(Debug)]
+pub(crate) struct Sessions {
+    next_id: AtomicU64,
+    map: Mutex<HashMap<SessionId, SessionInfo>>,
+    events: Sender<Event>,
+
+    /// Random value identifying this server instance, part of the build ID.
+    instance: u64,
+
+    /// Number of reloads sent so far, part of the build ID.
+    reloads: AtomicU64,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Self {
+            next_id: AtomicU64::new(0),
+            map: Mutex::new(HashMap::new()),
+            events: broadcast::channel(EVENT_CHANNEL_SIZE).0,
+            // `RandomState` is randomly seeded per process, which is all we
+            // need.
+            instance: RandomState::new().build_hasher().finish(),
+            reloads: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Sessions {
+    pub(crate) fn subscribe(&self) -> EventStream {
+        EventStream::new(self.events.subscribe())
+    }
+
+    /// Sends `event` to all event streams.
+    pub(crate) fn emit(&self, event: Event) {
+        // An error only means that nobody is listening.
+        let _ = self.events.send(event);
+    }
+
+    /// Registers a new session, which is removed again once the returned
+    /// guard is dropped.
+    pub(crate) fn add(
+        self: &Arc<Self>,
+        user_agent: Option<String>,
+        url: Option<String>,
+    ) -> SessionGuard {
+        let info = SessionInfo {
+            id: SessionId(self.next_id.fetch_add(1, Ordering::Relaxed)),
+            user_agent,
+            url,
+            connected_since: SystemTime::now(),
+        };
+
+        log::debug!("Browser session {} connected ({:?})", info.id, info.url);
+        self.map.lock().unwrap().insert(info.id, info.clone());
+        let id = info.id;
+        self.emit(Event::ClientConnected(info));
+        SessionGuard { sessions: Arc::clone(self), id }
+    }
+
+    /// Updates the URL of a session after it navigated without loading a new
+    /// page.
+    pub(crate) fn set_url(&self, id: SessionId, url: String) {
+        if let Some(info) = self.map.lock().unwrap().get_mut(&id) {
+            log::trace!("Session {} navigated to {}", id, url);
+            info.url = Some(url);
+        }
+    }
+
+    /// Returns whether the session with the given ID is selected by `filter`.
+    pub(crate) fn is_selected(&self, id: SessionId, filter: &SessionFilter) -> bool {
+        self.map.lock().unwrap().get(&id).is_some_and(|info| filter.matches(info))
+    }
+
+    /// Returns all sessions matching `filter`, ordered by ID.
+    pub(crate) fn list(&self, filter: &SessionFilter) -> Vec<SessionInfo> {
+        let mut out = self.map.lock().unwrap()
+            .values()
+            .filter(|s| filter.matches(s))
+            .cloned()
+            .collect::<Vec<_>>();
+        out.sort_by_key(|s| s.id);
+        out
+    }
+
+    /// Returns an ID that changes whenever the server is restarted or a reload
+    /// is sent. Reconnecting clients use it to decide whether they have to
+    /// reload.
+    pub(crate) fn build_id(&self) -> String {
+        format!("{:016x}-{}", self.instance, self.reloads.load(Ordering::SeqCst))
+    }
+
+    /// Has to be called whenever a reload is sent, to change the build ID.
+    pub(crate) fn note_reload(&self) {
+        self.reloads.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Returns the number of sessions matching `filter`.
+    pub(crate) fn count(&self, filter: &SessionFilter) -> usize {
+        self.map.lock().unwrap().values().filter(|s| filter.matches(s)).count()
+    }
+}
+
+/// Keeps a session registered while it is alive.
+#[derive(Debug)]
+pub(crate) struct SessionGuard {
+    sessions: Arc<Sessions>,
+    pub(crate) id: SessionId,
+}
+
+impl SessionGuard {
+    /// Returns whether this session is selected by `filter`.
+    pub(crate) fn is_selected(&self, filter: &SessionFilter) -> bool {
+        self.sessions.is_selected(self.id, filter)
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        log::debug!("Browser session {} disconnected", self.id);
+        let info = self.sessions.map.lock().unwrap().remove(&self.id);
+        if let Some(info) = info {
+            self.sessions.emit(Event::ClientDisconnected(info));
+        }
+    }
+}
+
+/// Matches `text` against `pattern` in which `*` matches any sequence of
+/// characters.
+pub(crate) fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts = parts.collect::<Vec<_>>();
+    let Some(last) = parts.pop() else {
+        // No `*` in the pattern.
+        return rest.is_empty();
+    };
+
+    for part in parts {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{SessionFilter, Sessions, wildcard_match};
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("http://localhost/", "http://localhost/"));
+        assert!(!wildcard_match("http://localhost/", "http://localhost/foo"));
+        assert!(wildcard_match("http://localhost/*", "http://localhost/foo"));
+        assert!(wildcard_match("*/docs/*", "http://localhost/docs/intro.html"));
+        assert!(!wildcard_match("*/docs/*", "http://localhost/blog/docs"));
+        assert!(wildcard_match("*.html", "http://localhost/a.html"));
+        assert!(wildcard_match("*a*a*", "aa"));
+        assert!(!wildcard_match("*ab*ab", "xab"));
+        assert!(wildcard_match("*", ""));
+    }
+
+    #[test]
+    fn url_changes() {
+        let sessions = Arc::new(Sessions::default());
+        let session = sessions.add(None, Some("http://localhost/".into()));
+        let docs = SessionFilter::Url("http://localhost/docs/*".into());
+        assert!(!session.is_selected(&docs));
+
+        sessions.set_url(session.id, "http://localhost/docs/intro".into());
+        assert!(session.is_selected(&docs));
+        assert_eq!(
+            sessions.list(&docs)[0].url.as_deref(),
+            Some("http://localhost/docs/intro"),
+        );
+
+        let mut events = sessions.subscribe();
+        drop(session);
+        assert_eq!(sessions.count(&SessionFilter::All), 0);
+        let event = futures::executor::block_on(events.recv()).unwrap();
+        match event {
+            super::Event::ClientDisconnected(info) => {
+                assert_eq!(info.url.as_deref(), Some("http://localhost/docs/intro"));
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+}
@@ -15,6 +15,11 @@ mod traits {
         x
     }
 
+    fn event_stream() -> impl Send + Unpin + futures::Stream<Item = Event> {
+        let x: EventStream = todo!();
+        x
+    }
+
     fn server() -> impl Send + Unpin {
         let x: Server = todo!();
         x
@@ -73,3 +78,126 @@ fn parse_proxy_target_bad() {
         ProxyTargetParseError::HasPath,
     ));
 }
+
+#[test]
+fn diagnostic_json() {
+    let minimal: Diagnostic = serde_json::from_str(r#"{
+        "title": "Build failed",
+        "message": "oops"
+    }"#).unwrap();
+    assert_eq!(minimal, Diagnostic {
+        title: "Build failed".into(),
+        message: "oops".into(),
+        ..Default::default()
+    });
+    assert_eq!(minimal.severity, Severity::Error);
+
+    let full: Diagnostic = serde_json::from_str(r#"{
+        "title": "unused variable",
+        "file": "src/main.rs",
+        "line": 3,
+        "column": 9,
+        "message": "\u001b[33mwarning\u001b[0m: unused variable: `x`",
+        "code_frame": "let x = 5;",
+        "severity": "warning"
+    }"#).unwrap();
+    assert_eq!(full.file.as_deref(), Some("src/main.rs"));
+    assert_eq!(full.line, Some(3));
+    assert_eq!(full.column, Some(9));
+    assert_eq!(full.severity, Severity::Warning);
+
+    assert!(serde_json::from_str::<Diagnostic>(r#"{ "title": "x" }"#).is_err());
+    assert!(serde_json::from_str::<Diagnostic>(r#"{
+        "title": "x",
+        "message": "y",
+        "severity": "fatal"
+    }"#).is_err());
+}
+
+#[test]
+#[cfg(feature = "serde")]
+fn config_serde() {
+    let config: Config = serde_json::from_str(r#"{
+        "bind_addr": "0.0.0.0:4000",
+        "proxy": "localhost:8000",
+        "control_path": "/penguin/",
+        "mounts": [{ "uri_path": "assets/", "fs_path": "static" }],
+        "mime_types": { ".GLB": "model/gltf-binary" },
+        "inject_position": "head-start"
+    }"#).unwrap();
+    assert_eq!(config.bind_addr, ([0, 0, 0, 0], 4000).into());
+    assert_eq!(config.proxy(), Some(&"http://localhost:8000".parse().unwrap()));
+    assert_eq!(config.control_path(), "/penguin");
+    assert_eq!(config.mounts()[0].uri_path, "/assets");
+    assert!(!config.mounts()[0].follow_symlinks);
+    assert_eq!(config.mime_types()["glb"], "model/gltf-binary");
+    assert_eq!(config.inject_position(), InjectPosition::HeadStart);
+    assert!(config.preserve_state());
+
+    let json = serde_json::to_value(&config).unwrap();
+    assert_eq!(json["proxy"], "http://localhost:8000");
+    let roundtrip: Config = serde_json::from_value(json).unwrap();
+    assert_eq!(roundtrip.mounts()[0].uri_path, "/assets");
+
+    // `bind_addr` is optional as well.
+    let config: Config = serde_json::from_str(r#"{ "proxy": "localhost:8000" }"#).unwrap();
+    assert_eq!(config.bind_addr, ([127, 0, 0, 1], 4090).into());
+
+    // Configurations are validated: neither a proxy nor a mount...
+    assert!(serde_json::from_str::<Config>(r#"{ "bind_addr": "0.0.0.0:4000" }"#).is_err());
+    // ... and a proxy plus a mount on `/`.
+    assert!(serde_json::from_str::<Config>(r#"{
+        "bind_addr": "0.0.0.0:4000",
+        "proxy": "localhost:8000",
+        "mounts": [{ "uri_path": "/", "fs_path": "static" }]
+    }"#).is_err());
+}
+
+#[test]
+fn update_config() {
+    let (_server, controller) = Server::bind(([127, 0, 0, 1], 4090).into())
+        .add_mount("/", "public").unwrap()
+        .build()
+        .unwrap();
+
+    let new = Server::bind(([0, 0, 0, 0], 3000).into())
+        .add_mount("/assets", "static").unwrap()
+        .proxy("localhost:8000".parse().unwrap())
+        .validate()
+        .unwrap();
+    controller.select(SessionFilter::Url("*/docs/*".into())).update_config(new);
+
+    let config = controller.config.read().unwrap();
+    assert_eq!(config.bind_addr, ([127, 0, 0, 1], 4090).into());
+    assert_eq!(config.mounts()[0].uri_path, "/assets");
+    assert!(config.proxy().is_some());
+}
+
+#[test]
+fn invalid_header() {
+    let builder = Server::bind(([127, 0, 0, 1], 4090).into()).add_mount("/", "public").unwrap();
+    assert!(builder.clone().header("*", "X-Foo", "bar").validate().is_ok());
+    assert!(matches!(
+        builder.clone().header("*", "X Foo", "bar").validate(),
+        Err(ConfigError::InvalidHeader { .. }),
+    ));
+    assert!(matches!(
+        builder.header("*", "X-Foo", "a\nb").validate(),
+        Err(ConfigError::InvalidHeader { .. }),
+    ));
+}
+
+#[test]
+fn cors_credentials() {
+    let builder = Server::bind(([127, 0, 0, 1], 4090).into()).add_mount("/", "public").unwrap();
+    let cors = Cors { credentials: true, ..Cors::default() };
+    assert!(builder.clone().cors(Cors::permissive()).validate().is_ok());
+    assert!(builder.clone()
+        .cors(Cors { origins: vec!["http://localhost:3000".into()], ..cors.clone() })
+        .validate()
+        .is_ok());
+    assert!(matches!(
+        builder.cors(Cors { origins: vec!["*".into()], ..cors }).validate(),
+        Err(ConfigError::CorsCredentialsForAllOrigins),
+    ));
+}
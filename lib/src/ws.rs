@@ -1,55 +1,219 @@
+use std::sync::Arc;
+
 use futures::{SinkExt, StreamExt};
 use hyper_tungstenite::{HyperWebsocket, tungstenite::{Error, Message, error::ProtocolError}};
-use tokio::sync::broadcast::{Receiver, error::RecvError};
+use serde_json::{Map, Value, json};
+use tokio::sync::broadcast::{Receiver, Sender, error::RecvError};
+
+use crate::{serve::escape_html, session::{SessionGuard, Sessions}};
+use super::{Action, ConsoleLevel, Diagnostic, Event, SessionFilter, SessionId};
+
+
+/// The newest version of the JSON protocol the server speaks. See the crate
+/// docs for a description of the protocol.
+pub(crate) const PROTOCOL_VERSION: u32 = 1;
+
+/// Messages sent by the browser client.
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ClientMessage {
+    /// Sent by clients speaking the JSON protocol right after connecting.
+    Hello {
+        protocol: u32,
+
+        /// The commands the client understands. Other commands are not sent
+        /// to it.
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
 
-use super::Action;
+    Console {
+        level: ConsoleLevel,
+        message: String,
+    },
+
+    /// An event in sync mode. Its fields depend on the kind of event and are
+    /// only interpreted by the other browser sessions.
+    Sync(Map<String, Value>),
+
+    /// The page changed its URL without loading a new page.
+    Url {
+        url: String,
+    },
+}
+
+/// Commands sent to the browser client.
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum Command<'a> {
+    Reload,
+    Message { html: &'a str },
+    Error { diagnostic: &'a Diagnostic },
+    Clear,
+    Building,
+    Sync { event: &'a Map<String, Value> },
+}
+
+impl<'a> Command<'a> {
+    pub(crate) fn new(action: &'a Action) -> Self {
+        match action {
+            Action::Reload => Self::Reload,
+            Action::Message(html) => Self::Message { html },
+            Action::Error(diagnostic) => Self::Error { diagnostic },
+            Action::ClearMessage => Self::Clear,
+            Action::Building => Self::Building,
+            Action::Sync { event, .. } => Self::Sync { event },
+        }
+    }
+
+    /// The name of the command, which is also its `type` in the JSON protocol.
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Reload => "reload",
+            Self::Message { .. } => "message",
+            Self::Error { .. } => "error",
+            Self::Clear => "clear",
+            Self::Building => "building",
+            Self::Sync { .. } => "sync",
+        }
+    }
+
+    /// Encodes the command for the given protocol version, `None` being the
+    /// legacy text protocol: the command name, optionally followed by a
+    /// newline and payload. Legacy clients only understand `reload` and
+    /// `message` (see `LEGACY_CAPABILITIES`), so diagnostics are sent to them
+    /// as a message.
+    pub(crate) fn encode(&self, protocol: Option<u32>) -> String {
+        if protocol.is_some() {
+            return serde_json::to_string(self).expect("failed to serialize WS command");
+        }
+
+        match self {
+            Self::Message { html } => format!("message\n{}", html),
+            Self::Error { diagnostic } => format!("message\n{}", diagnostic_html(diagnostic)),
+            Self::Sync { event } => format!("sync\n{}", Value::Object((*event).clone())),
+            _ => self.name().to_owned(),
+        }
+    }
+
+    /// The name of the command as received by clients speaking the given
+    /// protocol version. Legacy clients get diagnostics as `message`.
+    fn name_for(&self, protocol: Option<u32>) -> &'static str {
+        match self {
+            Self::Error { .. } if protocol.is_none() => "message",
+            _ => self.name(),
+        }
+    }
+}
+
+/// The commands understood by clients using the legacy text protocol, i.e.
+/// the ones that were there before the JSON protocol was introduced.
+const LEGACY_CAPABILITIES: &[&str] = &["reload", "message"];
+
+/// Renders a diagnostic as HTML for clients that only understand `message`.
+/// ANSI color codes are removed.
+fn diagnostic_html(diagnostic: &Diagnostic) -> String {
+    let mut out = format!("<h2>{}</h2>", escape_html(&diagnostic.title));
+    if let Some(file) = &diagnostic.file {
+        let mut location = file.clone();
+        for n in diagnostic.line.iter().chain(&diagnostic.column) {
+            location += &format!(":{}", n);
+        }
+        out += &format!("<p><code>{}</code></p>", escape_html(&location));
+    }
+    out += &format!("<pre>{}</pre>", escape_html(&strip_ansi(&diagnostic.message)));
+    if let Some(code_frame) = &diagnostic.code_frame {
+        out += &format!("<pre>{}</pre>", escape_html(&strip_ansi(code_frame)));
+    }
+    out
+}
+
+/// Removes ANSI escape sequences like `\x1b[31m` from `s`.
+fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip up to and including the final byte of the sequence.
+            if chars.next() == Some('[') {
+                chars.by_ref().find(|c| ('@'..='~').contains(c));
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
 
 
 /// Function to handle a single websocket (listen for incoming `Action`s and
 /// stop if the WS connection is closed). There is one task per WS connection.
+/// `protocol` is the JSON protocol version requested by the client, or `None`
+/// for the legacy text protocol. If `sync` is enabled, sync events from the
+/// browser are relayed to all other connections via `actions`.
 pub(crate) async fn handle_connection(
     websocket: HyperWebsocket,
-    mut actions: Receiver<Action>,
+    actions: Sender<(SessionFilter, Action)>,
+    sessions: Arc<Sessions>,
+    user_agent: Option<String>,
+    url: Option<String>,
+    protocol: Option<u32>,
+    sync: bool,
 ) {
+    let mut receiver = actions.subscribe();
     let mut websocket = match websocket.await {
         Ok(ws) => ws,
         Err(e) => {
             log::warn!("failed to establish websocket connection: {}", e);
+            sessions.emit(Event::ClientError { session: None, message: e.to_string() });
             return;
         }
     };
 
+    // The session is unregistered once this is dropped when this function
+    // returns.
+    let session = sessions.add(user_agent, url);
+
+    // Clients requesting a newer protocol than we know get the newest one we
+    // speak. They learn about that through our hello message.
+    let protocol = protocol.map(|v| v.min(PROTOCOL_VERSION));
+    if let Some(protocol) = protocol {
+        let hello = hello(protocol, session.id, None, &sessions);
+        if let Err(e) = websocket.send(Message::text(hello)).await {
+            log::warn!("Failed to send WS hello message: {}", e);
+        }
+    }
+
+    // Commands the client understands, as announced in its hello message. As
+    // long as it didn't send one, we assume it understands everything (see
+    // `frame` for legacy clients, which never send one).
+    let mut capabilities = None::<Vec<String>>;
+
     loop {
         tokio::select! {
-            action = actions.recv() => {
-                let data = match &action {
-                    // When all senders have closed, there is no reason to
-                    // continue keeping this task alive.
-                    Err(RecvError::Closed) => break,
-                    Err(RecvError::Lagged(skipped)) => {
-                        // I really can't imagine this happening: this would
-                        // mean this WS task was never awoken while many actions
-                        // were incoming.
-                        log::warn!(
-                            "Missed {} actions. Did you submit too many actions too quickly? \
-                                For example, this can happen by watching a directory where lots \
-                                of files change at the same time.",
-                            skipped,
-                        );
-                        continue;
-                    }
-                    Ok(Action::Reload) => {
-                        log::trace!("Sending reload WS command");
-                        "reload".to_string()
-                    }
-                    Ok(Action::Message(msg)) => {
-                        log::trace!("Sending message WS command");
-                        format!("message\n{}", msg)
-                    }
+            action = next_action(&mut receiver, &session) => {
+                // When all senders have closed, there is no reason to continue
+                // keeping this task alive.
+                let Some(action) = action else {
+                    break;
+                };
+
+                let Some(frame) = frame(&action, protocol, capabilities.as_deref()) else {
+                    log::debug!(
+                        "Not sending WS command for {:?} to session {} as it does not support it",
+                        action,
+                        session.id,
+                    );
+                    continue;
                 };
 
-                if let Err(e) = websocket.send(Message::text(data)).await {
+                log::trace!("Sending WS command for {:?}", action);
+                if let Err(e) = websocket.send(Message::text(frame)).await {
                     log::warn!("Failed to send WS message for action '{:?}': {}", action, e);
+                    sessions.emit(Event::ClientError {
+                        session: Some(session.id),
+                        message: format!("failed to send message: {}", e),
+                    });
                 }
             }
 
@@ -59,6 +223,19 @@ pub(crate) async fn handle_connection(
                     // function.
                     None | Some(Ok(Message::Close(_))) => break,
 
+                    Some(Ok(Message::Text(text))) => {
+                        let announced = handle_message(
+                            &text,
+                            session.id,
+                            &sessions,
+                            &actions,
+                            sync,
+                        );
+                        if announced.is_some() {
+                            capabilities = announced;
+                        }
+                    }
+
                     // The library tungstenite already handles ping requests
                     // internally, but we still have to "call into the library"
                     // for the pong packet to actually get sent.
@@ -91,6 +268,10 @@ pub(crate) async fn handle_connection(
                             "Error receiving WS message. Shutting down WS connection. Error: {}",
                             e,
                         );
+                        sessions.emit(Event::ClientError {
+                            session: Some(session.id),
+                            message: e.to_string(),
+                        });
                         break;
                     }
 
@@ -100,3 +281,185 @@ pub(crate) async fn handle_connection(
         };
     }
 }
+
+
+/// Returns the WS frame to send for `action` to a client speaking `protocol`
+/// with the given capabilities (`None` meaning all), or `None` if the client
+/// does not understand the command. Legacy clients (`protocol` being `None`)
+/// only ever get the commands in `LEGACY_CAPABILITIES`.
+fn frame(
+    action: &Action,
+    protocol: Option<u32>,
+    capabilities: Option<&[String]>,
+) -> Option<String> {
+    let command = Command::new(action);
+    let name = command.name_for(protocol);
+    let supported = match (protocol, capabilities) {
+        (None, _) => LEGACY_CAPABILITIES.contains(&name),
+        (Some(_), Some(c)) => c.iter().any(|c| c == name),
+        (Some(_), None) => true,
+    };
+    supported.then(|| command.encode(protocol))
+}
+
+/// Returns the hello message sent to clients speaking the JSON protocol right
+/// after connecting. `token` is only given to sessions connected via SSE or
+/// long-polling, see `fallback::Tokens`.
+pub(crate) fn hello(
+    protocol: u32,
+    session: SessionId,
+    token: Option<&str>,
+    sessions: &Sessions,
+) -> String {
+    let mut hello = json!({
+        "type": "hello",
+        "protocol": protocol,
+        "version": env!("CARGO_PKG_VERSION"),
+        "build_id": sessions.build_id(),
+        "session": session.to_string(),
+    });
+    if let Some(token) = token {
+        hello["token"] = token.into();
+    }
+    hello.to_string()
+}
+
+/// Waits for the next action addressed to `session`. Returns `None` once all
+/// senders are gone.
+pub(crate) async fn next_action(
+    receiver: &mut Receiver<(SessionFilter, Action)>,
+    session: &SessionGuard,
+) -> Option<Action> {
+    loop {
+        match receiver.recv().await {
+            Ok((filter, _)) if !session.is_selected(&filter) => {}
+            Ok((_, Action::Sync { from, .. })) if from == session.id => {}
+            Ok((_, action)) => return Some(action),
+            Err(RecvError::Closed) => return None,
+            Err(RecvError::Lagged(skipped)) => {
+                // I really can't imagine this happening: this would mean the
+                // task was never awoken while many actions were incoming.
+                log::warn!(
+                    "Missed {} actions. Did you submit too many actions too quickly? \
+                        For example, this can happen by watching a directory where lots \
+                        of files change at the same time.",
+                    skipped,
+                );
+            }
+        }
+    }
+}
+
+/// Handles a message sent by the client of `session`. If `sync` is enabled,
+/// sync events are relayed to all other sessions via `actions`. Returns the
+/// announced capabilities if the message is a hello.
+pub(crate) fn handle_message(
+    text: &str,
+    session: SessionId,
+    sessions: &Sessions,
+    actions: &Sender<(SessionFilter, Action)>,
+    sync: bool,
+) -> Option<Vec<String>> {
+    match serde_json::from_str::<ClientMessage>(text) {
+        Ok(ClientMessage::Hello { protocol, capabilities }) => {
+            log::debug!(
+                "Session {} speaks protocol {} with capabilities {:?}",
+                session,
+                protocol,
+                capabilities,
+            );
+            return Some(capabilities);
+        }
+        Ok(ClientMessage::Console { level, message }) => {
+            sessions.emit(Event::Console { session, level, message });
+        }
+        Ok(ClientMessage::Sync(event)) if sync => {
+            // An error only means that there are no other sessions.
+            let _ = actions.send((SessionFilter::All, Action::Sync { from: session, event }));
+        }
+        Ok(ClientMessage::Sync(_)) => {
+            log::debug!("Ignoring sync message as sync mode is disabled");
+        }
+        Ok(ClientMessage::Url { url }) => sessions.set_url(session, url),
+        Err(e) => log::debug!("Ignoring unknown message from client: {}", e),
+    }
+
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Map, Value, json};
+
+    use crate::{Action, Diagnostic};
+    use super::{Command, frame};
+
+    #[test]
+    fn encode_commands() {
+        let encode = |action: Action| {
+            let command = Command::new(&action);
+            let json = serde_json::from_str::<Value>(&command.encode(Some(1))).unwrap();
+            (command.encode(None), json)
+        };
+
+        assert_eq!(encode(Action::Reload), ("reload".into(), json!({ "type": "reload" })));
+        assert_eq!(encode(Action::ClearMessage), ("clear".into(), json!({ "type": "clear" })));
+        assert_eq!(
+            encode(Action::Message("<b>hi</b>".into())),
+            ("message\n<b>hi</b>".into(), json!({ "type": "message", "html": "<b>hi</b>" })),
+        );
+
+        let diagnostic = Diagnostic { title: "oops".into(), ..Default::default() };
+        let (legacy, json) = encode(Action::Error(diagnostic.clone()));
+        let diagnostic = serde_json::to_value(&diagnostic).unwrap();
+        assert_eq!(legacy, "message\n<h2>oops</h2><pre></pre>");
+        assert_eq!(json, json!({ "type": "error", "diagnostic": diagnostic }));
+    }
+
+    #[test]
+    fn legacy_frames() {
+        let diagnostic = Diagnostic {
+            title: "<oops>".into(),
+            file: Some("src/main.rs".into()),
+            line: Some(3),
+            message: "\x1b[31mexpected `;`\x1b[0m".into(),
+            ..Default::default()
+        };
+        let mut event = Map::new();
+        event.insert("kind".into(), "scroll".into());
+        let actions = [
+            Action::Reload,
+            Action::Message("<b>hi</b>".into()),
+            Action::Error(diagnostic),
+            Action::ClearMessage,
+            Action::Building,
+            Action::Sync { from: "1".parse().unwrap(), event },
+        ];
+
+        // Legacy clients never announce capabilities, but even if they did,
+        // they must only get commands the old script understands.
+        let all = ["reload", "message", "error", "clear", "building", "sync"]
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>();
+        for capabilities in [None, Some(&all[..])] {
+            let frames = actions.iter()
+                .filter_map(|action| frame(action, None, capabilities))
+                .collect::<Vec<_>>();
+            assert_eq!(frames, [
+                "reload",
+                "message\n<b>hi</b>",
+                "message\n<h2>&lt;oops&gt;</h2><p><code>src/main.rs:3</code></p>\
+                    <pre>expected `;`</pre>",
+            ]);
+        }
+
+        // JSON clients get everything they announced.
+        let sent = |capabilities| {
+            actions.iter().filter(|a| frame(a, Some(1), capabilities).is_some()).count()
+        };
+        assert_eq!(sent(None), actions.len());
+        assert_eq!(sent(Some(&all[..2])), 2);
+    }
+}